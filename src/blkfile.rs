@@ -0,0 +1,514 @@
+use bitcoin::consensus::{deserialize, encode, Decodable};
+use bitcoin::hashes::{sha256d, Hash, HashEngine};
+use bitcoin::util::uint::Uint256;
+use bitcoin::{Block, BlockHash, BlockHeader, Network, Script, VarInt};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+pub mod undo;
+
+#[cfg(test)]
+mod test;
+
+/// Name of the file with the obfuscation key in the blocks directory
+pub const XOR_FILE: &str = "xor.dat";
+
+/// Size of the obfuscation key
+pub const XOR_KEY_SIZE: usize = 8;
+
+/// Size of the checksum following undo data of a block
+const CHECKSUM_SIZE: usize = 32;
+
+/// Size of the header of a record: network magic and size of the data
+const RECORD_HEADER_SIZE: usize = 8;
+
+/// Errors of reading block files
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to read a file
+    Io(io::Error),
+    /// Malformed block or undo data
+    Encode(encode::Error),
+    /// A record doesn't start with the magic of the network
+    Magic { file: u32, offset: u64 },
+    /// The obfuscation key has a wrong size
+    XorKey(usize),
+    /// A block of the active chain is missing, e.g. files changed while reading
+    MissingBlock(BlockHash),
+}
+
+impl error::Error for Error {}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "block files I/O error: {}", e),
+            Error::Encode(e) => write!(f, "malformed block file: {}", e),
+            Error::Magic { file, offset } => write!(
+                f,
+                "unexpected network magic in file {} at offset {}",
+                file, offset
+            ),
+            Error::XorKey(size) => write!(
+                f,
+                "obfuscation key has {} bytes instead of {}",
+                size, XOR_KEY_SIZE
+            ),
+            Error::MissingBlock(hash) => write!(f, "block {} is missing", hash),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<encode::Error> for Error {
+    fn from(e: encode::Error) -> Self {
+        Error::Encode(e)
+    }
+}
+
+/// Reader of a file obfuscated with the key, every byte is xored with the key byte at the same
+/// position modulo the key size
+struct XorReader<R> {
+    inner: R,
+    key: [u8; XOR_KEY_SIZE],
+    pos: u64,
+}
+
+impl<R: Read + Seek> XorReader<R> {
+    fn seek(&mut self, pos: u64) -> Result<(), io::Error> {
+        self.pos = self.inner.seek(SeekFrom::Start(pos))?;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for XorReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        let n = self.inner.read(buf)?;
+        for (i, byte) in buf[..n].iter_mut().enumerate() {
+            *byte ^= self.key[(self.pos as usize + i) % XOR_KEY_SIZE];
+        }
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+/// A block read from a `blk*.dat` file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileBlock {
+    /// The block itself
+    pub block: Block,
+    /// Scripts spent by the block in order of inputs, skipping the coinbase, `None` if the block
+    /// has no undo data
+    pub prevouts: Option<Vec<Script>>,
+}
+
+/// Undo data of a block as stored in a `rev*.dat` file
+struct UndoRecord<'a> {
+    data: &'a [u8],
+    checksum: &'a [u8],
+    scripts: Vec<Vec<Script>>,
+}
+
+/// Blocks and undo data of the `blocks` directory of Bitcoin Core.
+///
+/// Blocks are stored in `blk*.dat` files in order of download, undo data of a block is stored in
+/// the `rev*.dat` file with the same number, also not in order. Undo data is matched to blocks
+/// by its checksum, which commits to the previous block hash. Both files are obfuscated with the
+/// key from `xor.dat` if it exists.
+#[derive(Debug, Clone)]
+pub struct BlockFiles {
+    dir: PathBuf,
+    magic: u32,
+    key: [u8; XOR_KEY_SIZE],
+}
+
+impl BlockFiles {
+    /// Open the blocks directory of a node of the network
+    pub fn open<P: AsRef<Path>>(dir: P, network: Network) -> Result<BlockFiles, Error> {
+        let dir = dir.as_ref().to_path_buf();
+        let mut key = [0; XOR_KEY_SIZE];
+        match fs::read(dir.join(XOR_FILE)) {
+            Ok(bytes) if bytes.len() == XOR_KEY_SIZE => key.copy_from_slice(&bytes),
+            Ok(bytes) => return Err(Error::XorKey(bytes.len())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e.into()),
+        }
+        Ok(BlockFiles {
+            dir,
+            magic: network.magic(),
+            key,
+        })
+    }
+
+    /// The blocks directory
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Path of the `blk*.dat` file with the number
+    pub fn blk_path(&self, file: u32) -> PathBuf {
+        self.dir.join(format!("blk{:05}.dat", file))
+    }
+
+    /// Path of the `rev*.dat` file with the number
+    pub fn rev_path(&self, file: u32) -> PathBuf {
+        self.dir.join(format!("rev{:05}.dat", file))
+    }
+
+    /// Numbers of all `blk*.dat` files in ascending order
+    pub fn file_numbers(&self) -> Result<Vec<u32>, Error> {
+        let mut numbers = vec![];
+        for entry in fs::read_dir(&self.dir)? {
+            let name = entry?.file_name();
+            let number = name
+                .to_str()
+                .and_then(|name| name.strip_prefix("blk"))
+                .and_then(|name| name.strip_suffix(".dat"))
+                .and_then(|number| number.parse().ok());
+            if let Some(number) = number {
+                numbers.push(number);
+            }
+        }
+        numbers.sort_unstable();
+        Ok(numbers)
+    }
+
+    /// Read blocks of the file in order of storage
+    pub fn read_blocks(&self, file: u32) -> Result<Vec<Block>, Error> {
+        self.read_records(file, &self.blk_path(file), 0)?
+            .iter()
+            .map(|data| Ok(deserialize(data)?))
+            .collect()
+    }
+
+    /// Read headers of blocks of the file in order of storage, skipping the rest of the data
+    pub fn read_headers(&self, file: u32) -> Result<Vec<BlockHeader>, Error> {
+        Ok(self
+            .read_header_records(file)?
+            .into_iter()
+            .map(|(header, _)| header)
+            .collect())
+    }
+
+    /// Headers of blocks of the file with amounts of their transactions
+    fn read_header_records(&self, file: u32) -> Result<Vec<(BlockHeader, u64)>, Error> {
+        let file_len = fs::metadata(self.blk_path(file))?.len();
+        let mut reader = self.reader(&self.blk_path(file))?;
+        let mut headers = vec![];
+        let mut offset = 0;
+        while offset + RECORD_HEADER_SIZE as u64 <= file_len {
+            reader.seek(offset)?;
+            let mut magic = [0; 4];
+            reader.read_exact(&mut magic)?;
+            if self.is_padding(offset, &magic) {
+                break;
+            } else if u32::consensus_decode(&magic[..])? != self.magic {
+                return Err(Error::Magic { file, offset });
+            }
+            let size = u32::consensus_decode(&mut reader)? as u64;
+            let next = offset + RECORD_HEADER_SIZE as u64 + size;
+            if next > file_len {
+                break;
+            }
+            let header = BlockHeader::consensus_decode(&mut reader)?;
+            headers.push((header, VarInt::consensus_decode(&mut reader)?.0));
+            offset = next;
+        }
+        Ok(headers)
+    }
+
+    /// Read blocks of the file with scripts spent by them, in order of inputs and skipping
+    /// coinbases.
+    ///
+    /// Spent scripts are `None` for blocks without undo data, i.e. never connected ones. The
+    /// genesis block spends nothing and has no undo data.
+    pub fn read_file(&self, file: u32) -> Result<Vec<FileBlock>, Error> {
+        let blocks = self.read_blocks(file)?;
+        let rev_path = self.rev_path(file);
+        let records = if rev_path.exists() {
+            self.read_records(file, &rev_path, CHECKSUM_SIZE)?
+        } else {
+            vec![]
+        };
+        // undo data by amounts of inputs of transactions to check few checksums per block
+        let mut undos: HashMap<Vec<usize>, Vec<UndoRecord>> = HashMap::new();
+        for record in &records {
+            let (data, checksum) = record.split_at(record.len() - CHECKSUM_SIZE);
+            let scripts = undo::decode_block_undo(data)?;
+            let shape = scripts.iter().map(|s| s.len()).collect();
+            undos.entry(shape).or_default().push(UndoRecord {
+                data,
+                checksum,
+                scripts,
+            });
+        }
+        Ok(blocks
+            .into_iter()
+            .map(|block| {
+                let shape: Vec<usize> = block
+                    .txdata
+                    .iter()
+                    .filter(|tx| !tx.is_coin_base())
+                    .map(|tx| tx.input.len())
+                    .collect();
+                let prev = block.header.prev_blockhash;
+                let found = undos.get_mut(&shape).and_then(|candidates| {
+                    let i = candidates
+                        .iter()
+                        .position(|u| undo_checksum(&prev, u.data)[..] == *u.checksum)?;
+                    Some(candidates.swap_remove(i).scripts)
+                });
+                let prevouts = match found {
+                    Some(scripts) => Some(scripts.into_iter().flatten().collect()),
+                    None if prev == BlockHash::default() => Some(vec![]),
+                    None => None,
+                };
+                FileBlock { block, prevouts }
+            })
+            .collect())
+    }
+
+    /// Blocks of the chain ending at the connected block with most work, with their spent scripts
+    /// in order of height.
+    ///
+    /// Headers and undo data of all files are read first to find the chain, blocks are stored
+    /// before they are connected and only the ones with undo data can end it. Then files are read
+    /// one by one keeping blocks stored ahead of their parents in memory.
+    ///
+    /// Files of a pruned data directory don't start with the genesis block, see
+    /// `ChainBlocks::base`.
+    pub fn active_chain(&self) -> Result<ChainBlocks<'_>, Error> {
+        let files = self.file_numbers()?;
+        let mut headers = HashMap::new();
+        let mut order = vec![];
+        let mut connected = HashSet::new();
+        for file in &files {
+            let records = self.read_header_records(*file)?;
+            connected.extend(self.connected_blocks(*file, &records)?);
+            for (header, _) in records {
+                let hash = header.block_hash();
+                if headers.insert(hash, header).is_none() {
+                    order.push(hash);
+                }
+            }
+        }
+        let chain = best_chain(&headers, &order, &connected);
+        let base = chain
+            .first()
+            .map_or_else(BlockHash::default, |hash| headers[hash].prev_blockhash);
+        Ok(ChainBlocks {
+            files: self,
+            base,
+            heights: chain
+                .iter()
+                .enumerate()
+                .map(|(h, hash)| (*hash, h))
+                .collect(),
+            chain,
+            remaining: files.into_iter().collect(),
+            height: 0,
+            pending: HashMap::new(),
+        })
+    }
+
+    /// Hashes of blocks of the file with undo data. Undo data starts with the amount of
+    /// non-coinbase transactions, only blocks with as many transactions are checked against its
+    /// checksum.
+    fn connected_blocks(
+        &self,
+        file: u32,
+        headers: &[(BlockHeader, u64)],
+    ) -> Result<HashSet<BlockHash>, Error> {
+        let mut connected = HashSet::new();
+        let rev_path = self.rev_path(file);
+        if !rev_path.exists() {
+            return Ok(connected);
+        }
+        for record in self.read_records(file, &rev_path, CHECKSUM_SIZE)? {
+            let (data, checksum) = record.split_at(record.len() - CHECKSUM_SIZE);
+            let n_txs = VarInt::consensus_decode(data)?.0;
+            let found = headers.iter().find(|(header, n)| {
+                *n == n_txs + 1 && undo_checksum(&header.prev_blockhash, data)[..] == *checksum
+            });
+            if let Some((header, _)) = found {
+                connected.insert(header.block_hash());
+            }
+        }
+        Ok(connected)
+    }
+
+    fn reader(&self, path: &Path) -> Result<XorReader<File>, Error> {
+        Ok(XorReader {
+            inner: File::open(path)?,
+            key: self.key,
+            pos: 0,
+        })
+    }
+
+    /// Check whether the de-xored bytes at the offset are zero in the file. Preallocated space
+    /// at the end of a file is not obfuscated.
+    fn is_padding(&self, offset: u64, bytes: &[u8]) -> bool {
+        bytes
+            .iter()
+            .enumerate()
+            .all(|(i, byte)| *byte == self.key[(offset as usize + i) % XOR_KEY_SIZE])
+    }
+
+    /// Read data of all records of the file, each followed by `trailer` bytes. Zero padding and
+    /// a partially written record at the end are skipped.
+    fn read_records(&self, file: u32, path: &Path, trailer: usize) -> Result<Vec<Vec<u8>>, Error> {
+        let mut bytes = vec![];
+        self.reader(path)?.read_to_end(&mut bytes)?;
+        let mut records = vec![];
+        let mut offset = 0;
+        while offset + RECORD_HEADER_SIZE <= bytes.len() {
+            let mut header = &bytes[offset..offset + RECORD_HEADER_SIZE];
+            if self.is_padding(offset as u64, &header[..4]) {
+                break;
+            } else if u32::consensus_decode(&mut header)? != self.magic {
+                return Err(Error::Magic {
+                    file,
+                    offset: offset as u64,
+                });
+            }
+            let size = u32::consensus_decode(&mut header)? as usize;
+            let start = offset + RECORD_HEADER_SIZE;
+            let end = start + size + trailer;
+            if end > bytes.len() {
+                break;
+            }
+            records.push(bytes[start..end].to_vec());
+            offset = end;
+        }
+        Ok(records)
+    }
+}
+
+/// Iterator over blocks of the active chain, see `BlockFiles::active_chain`.
+///
+/// Items are ready to be passed to `FilterIndex::connect_block`. Iteration stops after the
+/// first error.
+pub struct ChainBlocks<'a> {
+    files: &'a BlockFiles,
+    base: BlockHash,
+    chain: Vec<BlockHash>,
+    heights: HashMap<BlockHash, usize>,
+    remaining: VecDeque<u32>,
+    height: usize,
+    pending: HashMap<usize, (Block, Vec<Script>)>,
+}
+
+impl ChainBlocks<'_> {
+    /// Hashes of blocks of the active chain in order of height. Blocks after the first one
+    /// without undo data are dropped once it is read.
+    pub fn chain(&self) -> &[BlockHash] {
+        &self.chain
+    }
+
+    /// Parent of the first block of the chain, the zero hash if the chain starts with the
+    /// genesis block. Pass it to `FilterIndex::with_base` to index a pruned chain.
+    pub fn base(&self) -> BlockHash {
+        self.base
+    }
+
+    fn fail(&mut self, e: Error) -> Option<Result<(Block, Vec<Script>), Error>> {
+        self.height = self.chain.len();
+        Some(Err(e))
+    }
+}
+
+impl Iterator for ChainBlocks<'_> {
+    type Item = Result<(Block, Vec<Script>), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.height >= self.chain.len() {
+                return None;
+            }
+            if let Some(item) = self.pending.remove(&self.height) {
+                self.height += 1;
+                return Some(Ok(item));
+            }
+            let file = match self.remaining.pop_front() {
+                Some(file) => file,
+                None => return self.fail(Error::MissingBlock(self.chain[self.height])),
+            };
+            let blocks = match self.files.read_file(file) {
+                Ok(blocks) => blocks,
+                Err(e) => return self.fail(e),
+            };
+            for FileBlock { block, prevouts } in blocks {
+                let hash = block.block_hash();
+                let height = match self.heights.get(&hash) {
+                    Some(height) if *height >= self.height && *height < self.chain.len() => *height,
+                    _ => continue,
+                };
+                match prevouts {
+                    Some(prevouts) => {
+                        self.pending.insert(height, (block, prevouts));
+                    }
+                    // never connected, neither are its descendants
+                    None => {
+                        self.chain.truncate(height);
+                        self.pending.retain(|h, _| *h < height);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Checksum of undo data of a block as in Bitcoin Core
+fn undo_checksum(prev: &BlockHash, data: &[u8]) -> sha256d::Hash {
+    let mut engine = sha256d::Hash::engine();
+    engine.input(&prev[..]);
+    engine.input(data);
+    sha256d::Hash::from_engine(engine)
+}
+
+/// Hashes of the chain ending at the connected block with most work, starting from a block whose
+/// parent is unknown. The first one in `order` wins a tie, the genesis block needs no undo data.
+fn best_chain(
+    headers: &HashMap<BlockHash, BlockHeader>,
+    order: &[BlockHash],
+    connected: &HashSet<BlockHash>,
+) -> Vec<BlockHash> {
+    let mut children: HashMap<BlockHash, Vec<BlockHash>> = HashMap::new();
+    let mut queue = VecDeque::new();
+    for hash in order {
+        let prev = headers[hash].prev_blockhash;
+        if headers.contains_key(&prev) {
+            children.entry(prev).or_default().push(*hash);
+        } else {
+            queue.push_back((*hash, headers[hash].work()));
+        }
+    }
+    let mut best: Option<(BlockHash, Uint256)> = None;
+    while let Some((hash, work)) = queue.pop_front() {
+        let is_connected =
+            connected.contains(&hash) || headers[&hash].prev_blockhash == BlockHash::default();
+        if is_connected && best.map_or(true, |(_, best_work)| work > best_work) {
+            best = Some((hash, work));
+        }
+        for child in children.get(&hash).into_iter().flatten() {
+            queue.push_back((*child, work + headers[child].work()));
+        }
+    }
+    let mut chain = vec![];
+    let mut next = best.map(|(hash, _)| hash);
+    while let Some(hash) = next {
+        chain.push(hash);
+        next = Some(headers[&hash].prev_blockhash).filter(|prev| headers.contains_key(prev));
+    }
+    chain.reverse();
+    chain
+}
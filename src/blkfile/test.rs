@@ -0,0 +1,297 @@
+use crate::blkfile::undo::{decode_script, decompress_script, read_varint, write_varint};
+use crate::blkfile::{BlockFiles, ChainBlocks, XOR_FILE};
+use crate::btc::ErgveinFilter;
+use crate::index::FilterIndex;
+use crate::policy::{FilterVersion, MissingUtxoPolicy};
+use crate::store::MemoryFilterStore;
+use crate::test::utils::*;
+use bitcoin::blockdata::script::Builder;
+use bitcoin::consensus::{serialize, Encodable};
+use bitcoin::hashes::hex::FromHex;
+use bitcoin::hashes::{sha256d, Hash, HashEngine};
+use bitcoin::{
+    Block, BlockHash, BlockHeader, FilterHeader, Network, OutPoint, Script, Transaction, TxIn,
+    TxOut, VarInt,
+};
+use std::fs;
+use std::path::Path;
+
+fn make_block(prev: BlockHash, bits: u32, tag: u8) -> Block {
+    let coinbase = Transaction {
+        version: 1,
+        lock_time: 0,
+        input: vec![TxIn {
+            previous_output: OutPoint::null(),
+            script_sig: Builder::new().push_slice(&[tag]).into_script(),
+            sequence: 0xffffffff,
+            witness: vec![],
+        }],
+        output: vec![TxOut {
+            value: 50,
+            script_pubkey: Builder::new()
+                .push_int(0)
+                .push_slice(&[tag; 20])
+                .into_script(),
+        }],
+    };
+    Block {
+        header: BlockHeader {
+            version: 1,
+            prev_blockhash: prev,
+            merkle_root: Default::default(),
+            time: tag as u32,
+            bits,
+            nonce: 0,
+        },
+        txdata: vec![coinbase],
+    }
+}
+
+/// Encode a script as in the UTXO database, compressing only P2PKH ones
+fn compress_script(script: &Script) -> Vec<u8> {
+    let mut bytes = vec![];
+    if script.is_p2pkh() {
+        write_varint(&mut bytes, 0).unwrap();
+        bytes.extend_from_slice(&script[3..23]);
+    } else {
+        write_varint(&mut bytes, script.len() as u64 + 6).unwrap();
+        bytes.extend_from_slice(script.as_bytes());
+    }
+    bytes
+}
+
+/// Undo data of a block with its checksum
+fn make_undo(block: &Block, prevouts: &[Script]) -> Vec<u8> {
+    let mut prevouts = prevouts.iter();
+    let txs: Vec<&Transaction> = block
+        .txdata
+        .iter()
+        .filter(|tx| !tx.is_coin_base())
+        .collect();
+    let mut data = serialize(&VarInt(txs.len() as u64));
+    for tx in txs {
+        VarInt(tx.input.len() as u64)
+            .consensus_encode(&mut data)
+            .unwrap();
+        for _ in &tx.input {
+            write_varint(&mut data, 100 << 1).unwrap();
+            write_varint(&mut data, 0).unwrap();
+            write_varint(&mut data, 12345).unwrap();
+            data.extend_from_slice(&compress_script(prevouts.next().unwrap()));
+        }
+    }
+    let mut engine = sha256d::Hash::engine();
+    engine.input(&block.header.prev_blockhash[..]);
+    engine.input(&data);
+    data.extend_from_slice(&sha256d::Hash::from_engine(engine)[..]);
+    data
+}
+
+/// Write records of a file, `trailer` bytes at the end of a record are not counted in its size
+fn write_file(path: &Path, key: &[u8; 8], records: &[(Vec<u8>, usize)]) {
+    let mut bytes = vec![];
+    for (record, trailer) in records {
+        bytes.extend_from_slice(&serialize(&Network::Testnet.magic()));
+        bytes.extend_from_slice(&serialize(&((record.len() - trailer) as u32)));
+        bytes.extend_from_slice(record);
+    }
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte ^= key[i % 8];
+    }
+    // preallocated space is not obfuscated
+    bytes.extend_from_slice(&[0; 16]);
+    fs::write(path, bytes).unwrap();
+}
+
+struct DataDir {
+    /// Blocks of the active chain with their spent scripts
+    chain: Vec<(Block, Vec<Script>)>,
+    /// A never connected block
+    stale: Block,
+    /// A never connected block extending the active chain
+    unconnected: Block,
+}
+
+/// Write two files of blocks out of order with a stale branch that has the most work
+fn make_data_dir(dir: &Path, key: &[u8; 8]) -> DataDir {
+    let block1 = load_block("./test/block1");
+    let txmap = block1_inputs();
+    let prevouts = block_prevouts(&block1, &txmap);
+    let bits = block1.header.bits;
+    let s1 = make_block(block1.block_hash(), bits, 1);
+    let s2 = make_block(s1.block_hash(), bits, 2);
+    let s3 = make_block(s2.block_hash(), bits, 3);
+    let stale = make_block(s1.block_hash(), bits, 12);
+    let unconnected = make_block(s3.block_hash(), bits, 4);
+    // a never connected branch with more work than the active chain
+    let mut branch = vec![stale.clone()];
+    for tag in 13..16 {
+        branch.push(make_block(branch.last().unwrap().block_hash(), bits, tag));
+    }
+
+    let undo = |block: &Block, prevouts: &[Script]| (make_undo(block, prevouts), 32);
+    let block = |block: &Block| (serialize(block), 0);
+    write_file(
+        &dir.join("blk00000.dat"),
+        key,
+        &[block(&s2), block(&block1), block(&stale)],
+    );
+    write_file(
+        &dir.join("rev00000.dat"),
+        key,
+        &[undo(&block1, &prevouts), undo(&s2, &[])],
+    );
+    write_file(
+        &dir.join("blk00001.dat"),
+        key,
+        &[
+            block(&s1),
+            block(&unconnected),
+            block(&s3),
+            block(&branch[1]),
+            block(&branch[2]),
+            block(&branch[3]),
+        ],
+    );
+    write_file(
+        &dir.join("rev00001.dat"),
+        key,
+        &[undo(&s3, &[]), undo(&s1, &[])],
+    );
+    if key != &[0; 8] {
+        fs::write(dir.join(XOR_FILE), key).unwrap();
+    }
+    DataDir {
+        chain: vec![(block1, prevouts), (s1, vec![]), (s2, vec![]), (s3, vec![])],
+        stale,
+        unconnected,
+    }
+}
+
+#[test]
+fn varints() {
+    let cases: &[(u64, &str)] = &[
+        (0, "00"),
+        (127, "7f"),
+        (128, "8000"),
+        (255, "807f"),
+        (16383, "fe7f"),
+        (16384, "ff00"),
+        (16511, "ff7f"),
+        (65535, "82fe7f"),
+        (1 << 32, "8efefeff00"),
+    ];
+    for (n, hex) in cases {
+        let bytes = Vec::from_hex(hex).unwrap();
+        let mut encoded = vec![];
+        write_varint(&mut encoded, *n).unwrap();
+        assert_eq!(encoded, bytes);
+        assert_eq!(read_varint(bytes.as_slice()).unwrap(), *n);
+    }
+    assert!(read_varint([0xff; 10].as_ref()).is_err());
+}
+
+#[test]
+fn compressed_scripts() {
+    let hash = [7; 20];
+    let p2pkh = decompress_script(0, &hash).unwrap();
+    assert!(p2pkh.is_p2pkh());
+    assert_eq!(&p2pkh[3..23], &hash);
+    assert!(decompress_script(1, &hash).unwrap().is_p2sh());
+
+    let x =
+        Vec::from_hex("79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798").unwrap();
+    let compressed = decompress_script(2, &x).unwrap();
+    assert_eq!(compressed.len(), 35);
+    assert_eq!(compressed[1], 2);
+    let uncompressed = decompress_script(4, &x).unwrap();
+    assert_eq!(
+        uncompressed.as_bytes(),
+        Vec::from_hex(
+            "410479be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798\
+             483ada7726a3c4655da4fbfc0e1108a8fd17b448a68554199c47d08ffb10d4b8ac"
+        )
+        .unwrap()
+        .as_slice()
+    );
+    // not a point of the curve
+    assert_eq!(decompress_script(4, &[0xff; 32]), None);
+
+    let mut bytes = vec![];
+    write_varint(&mut bytes, 10006 + 6).unwrap();
+    bytes.extend_from_slice(&[0; 10006]);
+    assert_eq!(decode_script(bytes.as_slice()).unwrap().as_bytes(), &[0x6a]);
+}
+
+#[test]
+fn read_files() {
+    for (name, key) in &[("plain", [0; 8]), ("xor", [1, 2, 3, 4, 5, 6, 7, 8])] {
+        let dir = temp_dir(name);
+        let data = make_data_dir(&dir, key);
+        let files = BlockFiles::open(&dir, Network::Testnet).unwrap();
+        assert_eq!(files.file_numbers().unwrap(), vec![0, 1]);
+
+        let read = files.read_file(0).unwrap();
+        assert_eq!(read.len(), 3);
+        assert_eq!(read[0].block, data.chain[2].0);
+        assert_eq!(read[0].prevouts.as_ref(), Some(&data.chain[2].1));
+        assert_eq!(read[1].block, data.chain[0].0);
+        assert_eq!(read[1].prevouts.as_ref(), Some(&data.chain[0].1));
+        assert_eq!(read[2].block, data.stale);
+        assert_eq!(read[2].prevouts, None);
+
+        let read = files.read_file(1).unwrap();
+        assert_eq!(read[1].block, data.unconnected);
+        assert_eq!(read[1].prevouts, None);
+
+        let mut blocks = files.active_chain().unwrap();
+        assert_eq!(blocks.base(), data.chain[0].0.header.prev_blockhash);
+        let chain: Vec<_> = blocks.by_ref().collect::<Result<_, _>>().unwrap();
+        assert_eq!(chain, data.chain);
+        assert_eq!(ChainBlocks::chain(&blocks).len(), data.chain.len());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
+#[test]
+fn filters_from_files() {
+    let dir = temp_dir("filters");
+    let data = make_data_dir(&dir, &[0; 8]);
+    let files = BlockFiles::open(&dir, Network::Testnet).unwrap();
+    let blocks = files.active_chain().unwrap();
+    let mut index = FilterIndex::with_base(
+        MemoryFilterStore::new(),
+        &FilterVersion::V0,
+        0,
+        0,
+        blocks.base(),
+        FilterHeader::default(),
+    );
+    for item in blocks {
+        let (block, prevouts) = item.unwrap();
+        index.connect_block(&block, &prevouts).unwrap();
+    }
+    assert_eq!(index.tip().unwrap().1, data.chain[3].0.block_hash());
+
+    let block1 = &data.chain[0].0;
+    let txmap = block1_inputs();
+    let (expected, _) = ErgveinFilter::new_script_filter(
+        block1,
+        &FilterVersion::V0,
+        MissingUtxoPolicy::Fail,
+        script_for_coin(&txmap),
+    )
+    .unwrap();
+    let stored = index.filter(&block1.block_hash()).unwrap().unwrap();
+    assert_eq!(stored.filter, expected);
+
+    // blocks after block1 were never connected
+    fs::remove_file(dir.join("rev00001.dat")).unwrap();
+    let chain: Vec<_> = files
+        .active_chain()
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    assert_eq!(chain, data.chain[..1]);
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,131 @@
+//! Decoding of block undo data of Bitcoin Core, as written to `rev*.dat` files.
+//!
+//! Undo data of a block is a list of undo records of its transactions, skipping the coinbase,
+//! and every record lists coins spent by inputs of the transaction in order. Coins are stored in
+//! the compressed form of the UTXO database.
+use bitcoin::consensus::{encode, Decodable};
+use bitcoin::secp256k1::PublicKey;
+use bitcoin::{Script, VarInt};
+use std::io;
+
+/// Scripts longer than this are not stored in undo data, as in Bitcoin Core
+pub const MAX_SCRIPT_SIZE: u64 = 10000;
+
+/// Amount of special script types of the script compression
+const SPECIAL_SCRIPTS: u64 = 6;
+
+/// Decode undo data of a block, returns scripts spent by every non-coinbase transaction
+pub fn decode_block_undo<R: io::Read>(mut reader: R) -> Result<Vec<Vec<Script>>, encode::Error> {
+    let n_txs = VarInt::consensus_decode(&mut reader)?.0;
+    let mut txs = Vec::with_capacity(n_txs.min(1 << 16) as usize);
+    for _ in 0..n_txs {
+        let n_coins = VarInt::consensus_decode(&mut reader)?.0;
+        let mut coins = Vec::with_capacity(n_coins.min(1 << 16) as usize);
+        for _ in 0..n_coins {
+            coins.push(decode_coin(&mut reader)?);
+        }
+        txs.push(coins);
+    }
+    Ok(txs)
+}
+
+/// Decode a spent coin, returns its script
+pub fn decode_coin<R: io::Read>(mut reader: R) -> Result<Script, encode::Error> {
+    let code = read_varint(&mut reader)?;
+    if code >> 1 > 0 {
+        // version of the transaction, always zero in new data
+        read_varint(&mut reader)?;
+    }
+    // compressed amount
+    read_varint(&mut reader)?;
+    decode_script(&mut reader)
+}
+
+/// Decode a script compressed as in the UTXO database of Bitcoin Core
+pub fn decode_script<R: io::Read>(mut reader: R) -> Result<Script, encode::Error> {
+    let size = read_varint(&mut reader)?;
+    if size < SPECIAL_SCRIPTS {
+        let mut data = vec![0; special_script_size(size)];
+        reader.read_exact(&mut data)?;
+        return Ok(decompress_script(size, &data).unwrap_or_default());
+    }
+    let size = size - SPECIAL_SCRIPTS;
+    if size > MAX_SCRIPT_SIZE {
+        // skipped by Bitcoin Core and replaced with an unspendable script
+        io::copy(&mut reader.take(size), &mut io::sink())?;
+        return Ok(Script::from(vec![0x6a]));
+    }
+    let mut data = vec![0; size as usize];
+    reader.read_exact(&mut data)?;
+    Ok(Script::from(data))
+}
+
+/// Restore a script of a special type, `None` for an invalid public key
+pub fn decompress_script(kind: u64, data: &[u8]) -> Option<Script> {
+    let mut script = vec![];
+    match kind {
+        0 => {
+            script.extend_from_slice(&[0x76, 0xa9, 20]);
+            script.extend_from_slice(data);
+            script.extend_from_slice(&[0x88, 0xac]);
+        }
+        1 => {
+            script.extend_from_slice(&[0xa9, 20]);
+            script.extend_from_slice(data);
+            script.push(0x87);
+        }
+        2 | 3 => {
+            script.push(33);
+            script.push(kind as u8);
+            script.extend_from_slice(data);
+            script.push(0xac);
+        }
+        4 | 5 => {
+            let mut compressed = vec![kind as u8 - 2];
+            compressed.extend_from_slice(data);
+            let key = PublicKey::from_slice(&compressed).ok()?;
+            script.push(65);
+            script.extend_from_slice(&key.serialize_uncompressed());
+            script.push(0xac);
+        }
+        _ => return None,
+    }
+    Some(Script::from(script))
+}
+
+/// Read a variable length integer of Bitcoin Core, not to be confused with `VarInt`
+pub fn read_varint<R: io::Read>(mut reader: R) -> Result<u64, encode::Error> {
+    let mut n: u64 = 0;
+    loop {
+        let byte = u8::consensus_decode(&mut reader)?;
+        if n > u64::MAX >> 7 {
+            return Err(encode::Error::ParseFailed("varint is too large"));
+        }
+        n = (n << 7) | (byte & 0x7f) as u64;
+        if byte & 0x80 == 0 {
+            return Ok(n);
+        }
+        n = n
+            .checked_add(1)
+            .ok_or(encode::Error::ParseFailed("varint is too large"))?;
+    }
+}
+
+/// Write a variable length integer of Bitcoin Core
+pub fn write_varint<W: io::Write>(mut writer: W, mut n: u64) -> Result<usize, io::Error> {
+    let mut bytes = vec![(n & 0x7f) as u8];
+    while n > 0x7f {
+        n = (n >> 7) - 1;
+        bytes.push((n & 0x7f) as u8 | 0x80);
+    }
+    bytes.reverse();
+    writer.write_all(&bytes)?;
+    Ok(bytes.len())
+}
+
+fn special_script_size(kind: u64) -> usize {
+    match kind {
+        0 | 1 => 20,
+        _ => 32,
+    }
+}
@@ -1,7 +1,18 @@
-use crate::util::*;
-use bitcoin::util::bip158::{BlockFilterReader, BlockFilterWriter, Error};
-use bitcoin::{Block, BlockHash, OutPoint, Script, Transaction};
-use std::io::Cursor;
+use crate::builder::{FilterBuilder, OutpointFilterBuilder};
+use crate::gcs::GcsFilterReader;
+use crate::policy::{FilterId, MissingUtxoPolicy, ScriptPolicy};
+use crate::util::{block_filter_keys, outpoint_element, GcsParams};
+use bitcoin::consensus::{encode, Decodable, Encodable};
+use bitcoin::hashes::Hash;
+use bitcoin::util::bip158::Error;
+use bitcoin::{
+    Address, Block, BlockHash, FilterHash, FilterHeader, OutPoint, Script, Transaction, Txid,
+    VarInt,
+};
+use std::io;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 
 #[cfg(test)]
 mod test;
@@ -9,9 +20,12 @@ mod test;
 /// A BIP158 like filter that diverge only in which data is added to the filter.
 ///
 /// Ergvein wallet adds only segwit scripts and data carrier to save bandwith for mobile clients.
+/// Which scripts are included depends on the `ScriptPolicy` used to build the filter.
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct ErgveinFilter {
     /// Golomb encoded filter
+    #[cfg_attr(feature = "serde", serde(with = "crate::util::serde_content"))]
     pub content: Vec<u8>,
 }
 
@@ -23,23 +37,80 @@ impl ErgveinFilter {
         }
     }
 
-    /// Compute a SCRIPT_FILTER that contains spent and output scripts
-    pub fn new_script_filter<M>(block: &Block, script_for_coin: M) -> Result<ErgveinFilter, Error>
+    /// Compute a SCRIPT_FILTER that contains spent and output scripts.
+    ///
+    /// Returns outpoints of inputs that were left out of the filter, they are collected only with
+    /// `MissingUtxoPolicy::Collect`.
+    pub fn new_script_filter<M>(
+        block: &Block,
+        policy: &dyn ScriptPolicy,
+        missing: MissingUtxoPolicy,
+        script_for_coin: M,
+    ) -> Result<(ErgveinFilter, Vec<OutPoint>), Error>
     where
         M: Fn(&OutPoint) -> Result<Script, Error>,
     {
-        let mut out = Cursor::new(Vec::new());
-        {
-            let mut writer = BlockFilterWriter::new(&mut out, block);
-            add_output_scripts(&mut writer, &block.txdata);
-            add_input_scripts(&mut writer, &block.txdata, script_for_coin)?;
-            writer.finish()?;
+        let mut builder = FilterBuilder::for_block(&block.block_hash(), policy, missing);
+        builder.add_transactions(&block.txdata, script_for_coin)?;
+        let skipped = builder.skipped().to_vec();
+        let filter = ErgveinFilter {
+            content: builder.finish()?,
+        };
+        Ok((filter, skipped))
+    }
+
+    /// Compute a SCRIPT_FILTER resolving all spent scripts of the block with one call.
+    ///
+    /// The resolver returns `None` for unknown outpoints, they are handled by `missing` and
+    /// reported back as in `new_script_filter`.
+    pub fn new_script_filter_batch<R>(
+        block: &Block,
+        policy: &dyn ScriptPolicy,
+        missing: MissingUtxoPolicy,
+        resolver: R,
+    ) -> Result<(ErgveinFilter, Vec<OutPoint>), Error>
+    where
+        R: FnOnce(&[OutPoint]) -> Result<Vec<Option<Script>>, Error>,
+    {
+        let mut builder = FilterBuilder::for_block(&block.block_hash(), policy, missing);
+        for tx in &block.txdata {
+            builder.defer_transaction(tx);
+        }
+        builder.resolve_pending(resolver)?;
+        let skipped = builder.skipped().to_vec();
+        let filter = ErgveinFilter {
+            content: builder.finish()?,
+        };
+        Ok((filter, skipped))
+    }
+
+    /// Compute an outpoint filter with txids of the block and outpoints spent by it, see
+    /// `FilterId::OUTPOINTS`
+    pub fn new_outpoint_filter(block: &Block) -> Result<ErgveinFilter, Error> {
+        let mut builder = OutpointFilterBuilder::for_block(&block.block_hash());
+        for tx in &block.txdata {
+            builder.add_transaction(tx);
         }
         Ok(ErgveinFilter {
-            content: out.into_inner(),
+            content: builder.finish()?,
         })
     }
 
+    /// Amount of elements in the filter, read from the head of the content
+    pub fn n_elements(&self) -> u64 {
+        GcsFilterReader::n_elements(self.content.as_slice())
+    }
+
+    /// Compute hash of the filter content
+    pub fn filter_hash(&self) -> FilterHash {
+        FilterHash::hash(self.content.as_slice())
+    }
+
+    /// Compute this filter's header in a chain of filters
+    pub fn filter_header(&self, previous_filter_header: &FilterHeader) -> FilterHeader {
+        self.filter_hash().filter_header(previous_filter_header)
+    }
+
     /// Match any transaction output scripts
     pub fn match_tx_outputs(
         &self,
@@ -56,8 +127,8 @@ impl ErgveinFilter {
         block_hash: &BlockHash,
         query: &mut dyn Iterator<Item = &[u8]>,
     ) -> Result<bool, Error> {
-        let filter_reader = BlockFilterReader::new(block_hash);
-        filter_reader.match_any(&mut Cursor::new(self.content.as_slice()), query)
+        self.reader(block_hash)
+            .match_any(self.content.as_slice(), query)
     }
 
     /// match all query pattern
@@ -66,7 +137,215 @@ impl ErgveinFilter {
         block_hash: &BlockHash,
         query: &mut dyn Iterator<Item = &[u8]>,
     ) -> Result<bool, Error> {
-        let filter_reader = BlockFilterReader::new(block_hash);
-        filter_reader.match_all(&mut Cursor::new(self.content.as_slice()), query)
+        self.reader(block_hash)
+            .match_all(self.content.as_slice(), query)
+    }
+
+    /// Find which query elements are in the filter, returns their indices in ascending order
+    pub fn match_set<T: AsRef<[u8]>>(
+        &self,
+        block_hash: &BlockHash,
+        query: &[T],
+    ) -> Result<Vec<usize>, Error> {
+        self.reader(block_hash)
+            .match_set(self.content.as_slice(), query)
+    }
+
+    /// Find which outpoints are spent according to an outpoint filter, returns their indices
+    pub fn match_outpoints(
+        &self,
+        block_hash: &BlockHash,
+        outpoints: &[OutPoint],
+    ) -> Result<Vec<usize>, Error> {
+        let query: Vec<_> = outpoints.iter().map(outpoint_element).collect();
+        self.match_set(block_hash, &query)
+    }
+
+    /// Find which transactions are created according to an outpoint filter, returns their indices
+    pub fn match_txids(&self, block_hash: &BlockHash, txids: &[Txid]) -> Result<Vec<usize>, Error> {
+        self.match_set(block_hash, txids)
+    }
+
+    /// Match script pubkeys of the addresses, reporting addresses the policy never indexes
+    pub fn match_addresses(
+        &self,
+        block_hash: &BlockHash,
+        policy: &dyn ScriptPolicy,
+        addresses: &[Address],
+    ) -> Result<AddressMatch, Error> {
+        AddressMatch::new(policy, addresses, |scripts| {
+            self.match_set(block_hash, scripts)
+        })
+    }
+
+    fn reader(&self, block_hash: &BlockHash) -> GcsFilterReader {
+        let (k0, k1) = block_filter_keys(block_hash);
+        GcsFilterReader::new(k0, k1, GcsParams::BIP158)
+    }
+}
+
+impl Encodable for ErgveinFilter {
+    fn consensus_encode<W: io::Write>(&self, writer: W) -> Result<usize, io::Error> {
+        self.content.consensus_encode(writer)
+    }
+}
+
+impl Decodable for ErgveinFilter {
+    fn consensus_decode<D: io::Read>(d: D) -> Result<Self, encode::Error> {
+        Ok(ErgveinFilter {
+            content: Decodable::consensus_decode(d)?,
+        })
+    }
+}
+
+/// Result of matching addresses against a filter, both fields are indices into the queried addresses
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AddressMatch {
+    /// Addresses whose scripts are in the filter
+    pub matched: Vec<usize>,
+    /// Addresses whose scripts are never added to filters of the policy, so they can't match
+    pub unindexable: Vec<usize>,
+}
+
+impl AddressMatch {
+    pub(crate) fn new<F>(
+        policy: &dyn ScriptPolicy,
+        addresses: &[Address],
+        match_set: F,
+    ) -> Result<AddressMatch, Error>
+    where
+        F: FnOnce(&[Script]) -> Result<Vec<usize>, Error>,
+    {
+        let mut indices = vec![];
+        let mut scripts = vec![];
+        let mut unindexable = vec![];
+        for (i, address) in addresses.iter().enumerate() {
+            let script = address.script_pubkey();
+            if policy.is_script_indexable(&script) {
+                indices.push(i);
+                scripts.push(script);
+            } else {
+                unindexable.push(i);
+            }
+        }
+        let matched = match_set(&scripts)?
+            .into_iter()
+            .map(|i| indices[i])
+            .collect();
+        Ok(AddressMatch {
+            matched,
+            unindexable,
+        })
+    }
+
+    /// Check whether any address is in the filter
+    pub fn is_match(&self) -> bool {
+        !self.matched.is_empty()
+    }
+}
+
+/// Self-describing serialized form of a filter to store in databases and send over the wire.
+///
+/// Filter type and version identify the `ScriptPolicy` the filter was built with, the block hash
+/// is also the key to match against the filter, so filters of other networks are never mixed up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "RawFilterRecord"))]
+pub struct FilterRecord {
+    /// Type of the filter, see `ScriptPolicy::filter_type`
+    pub filter_type: u8,
+    /// Version of the filter rules, see `ScriptPolicy::version`
+    pub version: u8,
+    /// Block the filter is built for
+    pub block_hash: BlockHash,
+    /// Amount of elements in the filter
+    pub n_elements: u64,
+    /// The filter itself
+    pub filter: ErgveinFilter,
+}
+
+/// Deserialized fields of a `FilterRecord` before the element count is checked
+#[cfg(feature = "serde")]
+#[derive(Deserialize)]
+struct RawFilterRecord {
+    filter_type: u8,
+    version: u8,
+    block_hash: BlockHash,
+    n_elements: u64,
+    filter: ErgveinFilter,
+}
+
+#[cfg(feature = "serde")]
+impl std::convert::TryFrom<RawFilterRecord> for FilterRecord {
+    type Error = &'static str;
+
+    fn try_from(raw: RawFilterRecord) -> Result<Self, Self::Error> {
+        FilterRecord {
+            filter_type: raw.filter_type,
+            version: raw.version,
+            block_hash: raw.block_hash,
+            n_elements: raw.n_elements,
+            filter: raw.filter,
+        }
+        .checked()
+    }
+}
+
+impl FilterRecord {
+    /// Check that the stored element count matches the filter
+    fn checked(self) -> Result<Self, &'static str> {
+        if self.n_elements != self.filter.n_elements() {
+            return Err("filter element count doesn't match its content");
+        }
+        Ok(self)
+    }
+
+    /// Wrap a filter of the given kind for the given block, see `ScriptPolicy::id`
+    pub fn new(id: FilterId, block_hash: BlockHash, filter: ErgveinFilter) -> Self {
+        FilterRecord {
+            filter_type: id.filter_type,
+            version: id.version,
+            block_hash,
+            n_elements: filter.n_elements(),
+            filter,
+        }
+    }
+
+    /// Kind of the filter in the record
+    pub fn id(&self) -> FilterId {
+        FilterId {
+            filter_type: self.filter_type,
+            version: self.version,
+        }
+    }
+
+    /// Check that the record holds a filter of the kind for the block
+    pub fn is_valid_for(&self, id: FilterId, block_hash: &BlockHash) -> bool {
+        self.id() == id && self.block_hash == *block_hash
+    }
+}
+
+impl Encodable for FilterRecord {
+    fn consensus_encode<W: io::Write>(&self, mut writer: W) -> Result<usize, io::Error> {
+        let mut len = self.filter_type.consensus_encode(&mut writer)?;
+        len += self.version.consensus_encode(&mut writer)?;
+        len += self.block_hash.consensus_encode(&mut writer)?;
+        len += VarInt(self.n_elements).consensus_encode(&mut writer)?;
+        len += self.filter.consensus_encode(&mut writer)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for FilterRecord {
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<Self, encode::Error> {
+        FilterRecord {
+            filter_type: Decodable::consensus_decode(&mut d)?,
+            version: Decodable::consensus_decode(&mut d)?,
+            block_hash: Decodable::consensus_decode(&mut d)?,
+            n_elements: VarInt::consensus_decode(&mut d)?.0,
+            filter: Decodable::consensus_decode(&mut d)?,
+        }
+        .checked()
+        .map_err(encode::Error::ParseFailed)
     }
 }
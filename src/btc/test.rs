@@ -1,12 +1,19 @@
-use crate::btc::ErgveinFilter;
+use crate::btc::{ErgveinFilter, FilterRecord};
+use crate::policy::{
+    is_wrapped_segwit_input, Bip158Basic, FilterId, FilterVersion, MissingUtxoPolicy, ScriptPolicy,
+    WrappedSegwit, OUTPOINT_FILTER_TYPE,
+};
 use crate::test::utils::*;
 use crate::util::is_script_indexable;
-use bitcoin::consensus::deserialize;
+use bitcoin::bech32::u5;
+use bitcoin::consensus::{deserialize, serialize};
 use bitcoin::hashes::hex::FromHex;
-use bitcoin::util::bip158::Error;
+use bitcoin::util::bip158::{BlockFilter, Error};
 use bitcoin::BlockHash;
+use bitcoin::Script;
+use bitcoin::{Address, Network, OutPoint};
 
-use bitcoin::Transaction;
+use bitcoin::{Transaction, TxIn, TxOut};
 
 #[test]
 fn block_00000000000000000007fc62780dee62d79ba02e7d325d7503e80c4da8b16b72() {
@@ -20,10 +27,7 @@ fn block_00000000000000000007fc62780dee62d79ba02e7d325d7503e80c4da8b16b72() {
     let tx_content = Vec::from_hex("01000000000101392550f02f8936c5675c2a23f79cc66f6738daa4164b8258a42c59d06c4fc9340000000000ffffffff021920980100000000160014e31594dfc81060a5626841f7a66fcfe0c4e35365678d000000000000160014b7ce167a800057b5bb715a48964739395e64341802483045022100fcf9097b918de57e5d3ad61ead9b68a061937aaeec653e0ac4a7a5e407dd506e02206e36e13bf6500aa0f1d38ae4727ef65ac3dc44c9660bbaa0f671c67e66c675ae0121028efb5bdfc12f462c8155793e17f732bf32fd7fff382288198e5e7e66cf97aabc00000000").unwrap();
     let tx: Transaction = deserialize(&tx_content).unwrap();
 
-    assert_eq!(
-        test_filter.match_tx_outputs(&block_hash, &tx).unwrap(),
-        true
-    );
+    assert!(test_filter.match_tx_outputs(&block_hash, &tx).unwrap());
 }
 
 #[test]
@@ -34,14 +38,20 @@ fn block_000000000000017c36b1c7c70f467244009c552e1732604a0f779fc6ff2d6112() {
     let block_hash = block.block_hash();
     let txs = &block.txdata.as_slice()[1..];
     let txmap = make_inputs_map(load_txs("./test/block1-txs"));
-    let filter = ErgveinFilter::new_script_filter(&block, |o| {
-        if let Some(s) = txmap.get(o) {
-            Ok(s.clone())
-        } else {
-            Err(Error::UtxoMissing(o.clone()))
-        }
-    })
-    .unwrap();
+    let filter = ErgveinFilter::new_script_filter(
+        &block,
+        &FilterVersion::V0,
+        MissingUtxoPolicy::Fail,
+        |o| {
+            if let Some(s) = txmap.get(o) {
+                Ok(s.clone())
+            } else {
+                Err(Error::UtxoMissing(*o))
+            }
+        },
+    )
+    .unwrap()
+    .0;
     assert_eq!(test_filter.content, filter.content);
     for (i, tx) in txs.iter().enumerate() {
         let is_indexable = tx
@@ -57,3 +67,322 @@ fn block_000000000000017c36b1c7c70f467244009c552e1732604a0f779fc6ff2d6112() {
         }
     }
 }
+
+#[test]
+fn taproot_outputs_v1() {
+    let filter_content = Vec::from_hex("13461a23a8ce05d6ce6a435b1d11d65707a3c6fce967152b8ae09f851d42505b3c41dd87b705d5f4cc2c3062ddcdfebe7a1e80").unwrap();
+    let mut block = load_block("./test/block1");
+    let block_hash = block.block_hash();
+    let mut txmap = block1_inputs();
+    let taproot = Script::new_witness_program(u5::try_from_u8(1).unwrap(), &[0x42; 32]);
+    let spent_taproot = Script::new_witness_program(u5::try_from_u8(1).unwrap(), &[0x43; 32]);
+    let witness_v2 = Script::new_witness_program(u5::try_from_u8(2).unwrap(), &[0x44; 32]);
+    // key path spend of a Taproot output created outside of the block
+    let spent = OutPoint::new(Default::default(), 7);
+    txmap.insert(spent, spent_taproot.clone());
+    block.txdata.push(Transaction {
+        version: 2,
+        lock_time: 0,
+        input: vec![TxIn {
+            previous_output: spent,
+            script_sig: Script::new(),
+            sequence: 0xffffffff,
+            witness: vec![vec![0x01; 64]],
+        }],
+        output: vec![
+            TxOut {
+                value: 1000,
+                script_pubkey: taproot.clone(),
+            },
+            TxOut {
+                value: 1000,
+                script_pubkey: witness_v2.clone(),
+            },
+        ],
+    });
+    let script_for_coin = script_for_coin(&txmap);
+
+    let filter_v0 = ErgveinFilter::new_script_filter(
+        &block,
+        &FilterVersion::V0,
+        MissingUtxoPolicy::Fail,
+        script_for_coin,
+    )
+    .unwrap()
+    .0;
+    assert_eq!(filter_v0.content, filter_content);
+    let filter_v1 = ErgveinFilter::new_script_filter(
+        &block,
+        &FilterVersion::V1,
+        MissingUtxoPolicy::Fail,
+        script_for_coin,
+    )
+    .unwrap()
+    .0;
+    let query = [&taproot, &spent_taproot, &witness_v2];
+    assert_eq!(
+        filter_v1.match_set(&block_hash, &query).unwrap(),
+        vec![0, 1, 2]
+    );
+    assert!(filter_v0.match_set(&block_hash, &query).unwrap().is_empty());
+    for script in query.iter() {
+        assert!(FilterVersion::V1.is_script_indexable(script));
+        assert!(!FilterVersion::V0.is_script_indexable(script));
+    }
+}
+
+#[test]
+fn bip158_basic_policy() {
+    let block = load_block("./test/block1");
+    let txmap = block1_inputs();
+    let script_for_coin = script_for_coin(&txmap);
+    let expected = BlockFilter::new_script_filter(&block, script_for_coin).unwrap();
+    let filter = ErgveinFilter::new_script_filter(
+        &block,
+        &Bip158Basic,
+        MissingUtxoPolicy::Fail,
+        script_for_coin,
+    )
+    .unwrap()
+    .0;
+    assert_eq!(expected.content, filter.content);
+}
+
+#[test]
+fn match_set_indices() {
+    let block = load_block("./test/block1");
+    let txmap = block1_inputs();
+    let script_for_coin = script_for_coin(&txmap);
+    let (filter, _) = ErgveinFilter::new_script_filter(
+        &block,
+        &FilterVersion::V0,
+        MissingUtxoPolicy::Fail,
+        script_for_coin,
+    )
+    .unwrap();
+    let block_hash = block.block_hash();
+
+    let mut query: Vec<Vec<u8>> = vec![b"not in the filter".to_vec()];
+    for tx in &block.txdata {
+        for o in &tx.output {
+            query.push(o.script_pubkey.to_bytes());
+        }
+    }
+    query.push(vec![]);
+    let expected: Vec<usize> = query
+        .iter()
+        .enumerate()
+        .filter(|(_, e)| {
+            filter
+                .match_any(&block_hash, &mut std::iter::once(e.as_slice()))
+                .unwrap()
+        })
+        .map(|(i, _)| i)
+        .collect();
+    let matched = filter.match_set(&block_hash, &query).unwrap();
+    assert!(!matched.is_empty());
+    assert!(matched.len() < query.len());
+    assert_eq!(expected, matched);
+    for (i, e) in query.iter().enumerate() {
+        if FilterVersion::V0.is_script_indexable(&Script::from(e.clone())) {
+            assert!(matched.contains(&i));
+        }
+    }
+
+    assert!(filter
+        .match_set::<&[u8]>(&block_hash, &[])
+        .unwrap()
+        .is_empty());
+    assert!(ErgveinFilter::new(&[0])
+        .match_set(&block_hash, &query)
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn match_addresses() {
+    let block = load_block("./test/block1");
+    let txmap = block1_inputs();
+    let script_for_coin = script_for_coin(&txmap);
+    let (filter, _) = ErgveinFilter::new_script_filter(
+        &block,
+        &FilterVersion::V0,
+        MissingUtxoPolicy::Fail,
+        script_for_coin,
+    )
+    .unwrap();
+    let block_hash = block.block_hash();
+
+    let addresses: Vec<Address> = block
+        .txdata
+        .iter()
+        .flat_map(|tx| tx.output.iter())
+        .filter_map(|o| Address::from_script(&o.script_pubkey, Network::Bitcoin))
+        .collect();
+    let result = filter
+        .match_addresses(&block_hash, &FilterVersion::V0, &addresses)
+        .unwrap();
+    assert!(result.is_match());
+    assert!(!result.unindexable.is_empty());
+    for (i, address) in addresses.iter().enumerate() {
+        let indexable = is_script_indexable(&address.script_pubkey());
+        assert_eq!(indexable, result.matched.contains(&i), "address #{}", i);
+        assert_eq!(
+            !indexable,
+            result.unindexable.contains(&i),
+            "address #{}",
+            i
+        );
+    }
+
+    let p2sh: Vec<Address> = addresses
+        .iter()
+        .filter(|a| a.script_pubkey().is_p2sh())
+        .cloned()
+        .collect();
+    assert!(!p2sh.is_empty());
+    let wrapped = WrappedSegwit(FilterVersion::V0);
+    let result = filter
+        .match_addresses(&block_hash, &wrapped, &p2sh)
+        .unwrap();
+    assert!(result.unindexable.is_empty());
+}
+
+#[test]
+fn outpoint_filter() {
+    let block = load_block("./test/block1");
+    let block_hash = block.block_hash();
+    let filter = ErgveinFilter::new_outpoint_filter(&block).unwrap();
+
+    let txids: Vec<_> = block.txdata.iter().map(|tx| tx.txid()).collect();
+    let spent: Vec<_> = block.txdata[1..]
+        .iter()
+        .flat_map(|tx| tx.input.iter().map(|i| i.previous_output))
+        .collect();
+    assert_eq!(filter.n_elements(), (txids.len() + spent.len()) as u64);
+    assert_eq!(
+        filter.match_txids(&block_hash, &txids).unwrap(),
+        (0..txids.len()).collect::<Vec<_>>()
+    );
+    assert_eq!(
+        filter.match_outpoints(&block_hash, &spent).unwrap(),
+        (0..spent.len()).collect::<Vec<_>>()
+    );
+
+    // outputs created in the block are not spent by it
+    let unspent: Vec<_> = txids
+        .iter()
+        .map(|txid| OutPoint::new(*txid, 1000))
+        .collect();
+    assert!(filter
+        .match_outpoints(&block_hash, &unspent)
+        .unwrap()
+        .is_empty());
+    let record = FilterRecord::new(FilterId::OUTPOINTS, block_hash, filter);
+    assert_eq!(record.filter_type, OUTPOINT_FILTER_TYPE);
+    assert!(record.is_valid_for(FilterId::OUTPOINTS, &block_hash));
+    assert!(!record.is_valid_for(FilterVersion::V0.id(), &block_hash));
+}
+
+#[test]
+fn wrapped_segwit_spends() {
+    let block = load_block("./test/block1");
+    let block_hash = block.block_hash();
+    let txmap = block1_inputs();
+    let script_for_coin = script_for_coin(&txmap);
+    let policy = WrappedSegwit(FilterVersion::V0);
+    let filter =
+        ErgveinFilter::new_script_filter(&block, &policy, MissingUtxoPolicy::Fail, script_for_coin)
+            .unwrap()
+            .0;
+
+    let spent: Vec<_> = block
+        .txdata
+        .iter()
+        .flat_map(|tx| tx.input.iter())
+        .filter(|i| is_wrapped_segwit_input(i))
+        .map(|i| txmap[&i.previous_output].clone())
+        .collect();
+    assert!(!spent.is_empty());
+    for script in spent {
+        assert!(script.is_p2sh());
+        assert!(!FilterVersion::V0.is_script_indexable(&script));
+        let mut query = std::iter::once(script.as_bytes());
+        assert!(filter.match_any(&block_hash, &mut query).unwrap());
+    }
+}
+
+#[test]
+fn missing_utxos() {
+    let block = load_block("./test/block1");
+    let missing: Vec<_> = block
+        .txdata
+        .iter()
+        .skip(1)
+        .flat_map(|tx| tx.input.iter())
+        .filter(|i| FilterVersion::V0.is_input_indexable(i))
+        .map(|i| i.previous_output)
+        .collect();
+    let script_for_coin = |o: &_| Err(Error::UtxoMissing(*o));
+    let build = |policy| {
+        ErgveinFilter::new_script_filter(&block, &FilterVersion::V0, policy, script_for_coin)
+    };
+
+    assert!(build(MissingUtxoPolicy::Fail).is_err());
+    let (skipped_filter, skipped) = build(MissingUtxoPolicy::Skip).unwrap();
+    assert!(skipped.is_empty());
+    let (collected_filter, collected) = build(MissingUtxoPolicy::Collect).unwrap();
+    assert_eq!(collected, missing);
+    assert_eq!(skipped_filter, collected_filter);
+}
+
+#[test]
+fn filter_record_encoding() {
+    let block = load_block("./test/block1");
+    let block_hash = block.block_hash();
+    let filter = ErgveinFilter::new(&Vec::from_hex("13461a23a8ce05d6ce6a435b1d11d65707a3c6fce967152b8ae09f851d42505b3c41dd87b705d5f4cc2c3062ddcdfebe7a1e80").unwrap());
+    let record = FilterRecord::new(FilterVersion::V0.id(), block_hash, filter);
+    assert_eq!(record.n_elements, 0x13);
+    assert!(record.is_valid_for(FilterVersion::V0.id(), &block_hash));
+    assert!(!record.is_valid_for(FilterVersion::V1.id(), &block_hash));
+    assert!(!record.is_valid_for(WrappedSegwit(FilterVersion::V0).id(), &block_hash));
+    assert!(!record.is_valid_for(FilterVersion::V0.id(), &block.header.prev_blockhash));
+
+    let bytes = serialize(&record);
+    assert_eq!(deserialize::<FilterRecord>(&bytes).unwrap(), record);
+    let mut corrupted = bytes.clone();
+    corrupted[34] += 1;
+    assert!(deserialize::<FilterRecord>(&corrupted).is_err());
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn filter_record_serde() {
+    let block = load_block("./test/block1");
+    let content = "13461a23a8ce05d6ce6a435b1d11d65707a3c6fce967152b8ae09f851d42505b3c41dd87b705d5f4cc2c3062ddcdfebe7a1e80";
+    let raw = Vec::from_hex(content).unwrap();
+    let record = FilterRecord::new(
+        FilterVersion::V0.id(),
+        block.block_hash(),
+        ErgveinFilter::new(&raw),
+    );
+
+    let json = serde_json::to_value(&record).unwrap();
+    assert_eq!(json["filter"]["content"], content);
+    assert_eq!(json["block_hash"], block.block_hash().to_string());
+    assert_eq!(
+        serde_json::from_value::<FilterRecord>(json).unwrap(),
+        record
+    );
+
+    let bytes = bincode::serialize(&record).unwrap();
+    assert!(bytes.windows(raw.len()).any(|w| w == raw.as_slice()));
+    assert_eq!(
+        bincode::deserialize::<FilterRecord>(&bytes).unwrap(),
+        record
+    );
+
+    let mut json = serde_json::to_value(&record).unwrap();
+    json["n_elements"] = (record.n_elements + 1).into();
+    assert!(serde_json::from_value::<FilterRecord>(json).is_err());
+}
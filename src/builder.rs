@@ -0,0 +1,279 @@
+use crate::gcs;
+use crate::mempool::MempoolFilterKey;
+use crate::policy::{MissingUtxoPolicy, ScriptPolicy};
+use crate::util::*;
+use bitcoin::util::bip158::Error;
+use bitcoin::{BlockHash, OutPoint, Script, Transaction, Txid};
+use std::borrow::Borrow;
+use std::collections::HashSet;
+use std::io;
+
+#[cfg(test)]
+mod test;
+
+/// Incrementally builds a SCRIPT_FILTER from transactions added one at a time.
+///
+/// Only the set of filter elements and ids of added transactions are kept, so memory use is
+/// proportional to the number of distinct indexed scripts and not to the size of the block or
+/// mempool.
+///
+/// Inputs that spend outputs of already added transactions are not passed to the script lookup:
+/// their scripts are added to the filter with the outputs. Transactions of a block are ordered
+/// so that parents come first, for an unordered set of mempool transactions add outputs of all
+/// of them before the inputs.
+pub struct FilterBuilder<'a> {
+    policy: &'a dyn ScriptPolicy,
+    missing: MissingUtxoPolicy,
+    skipped: Vec<OutPoint>,
+    k0: u64,
+    k1: u64,
+    params: GcsParams,
+    elements: HashSet<Vec<u8>>,
+    pending: Vec<OutPoint>,
+    txids: HashSet<Txid>,
+}
+
+impl<'a> FilterWriter for FilterBuilder<'a> {
+    fn add_filter_element(&mut self, data: &[u8]) {
+        self.add_element(data);
+    }
+    fn is_known_output(&self, outpoint: &OutPoint) -> bool {
+        self.txids.contains(&outpoint.txid)
+    }
+    fn on_missing_utxo(&mut self, outpoint: &OutPoint, error: Error) -> Result<(), Error> {
+        self.missing.handle(outpoint, error, &mut self.skipped)
+    }
+}
+
+impl<'a> FilterBuilder<'a> {
+    /// Create a builder for the filter of the block with given hash
+    pub fn for_block(
+        block_hash: &BlockHash,
+        policy: &'a dyn ScriptPolicy,
+        missing: MissingUtxoPolicy,
+    ) -> FilterBuilder<'a> {
+        let (k0, k1) = block_filter_keys(block_hash);
+        FilterBuilder {
+            policy,
+            missing,
+            skipped: vec![],
+            k0,
+            k1,
+            params: GcsParams::BIP158,
+            elements: HashSet::new(),
+            pending: vec![],
+            txids: HashSet::new(),
+        }
+    }
+
+    /// Create a builder for a mempool filter with given SipHash keys and coding parameters
+    pub fn for_mempool(
+        key: &MempoolFilterKey,
+        params: GcsParams,
+        policy: &'a dyn ScriptPolicy,
+        missing: MissingUtxoPolicy,
+    ) -> FilterBuilder<'a> {
+        FilterBuilder {
+            policy,
+            missing,
+            skipped: vec![],
+            k0: key.k0(),
+            k1: key.k1(),
+            params,
+            elements: HashSet::new(),
+            pending: vec![],
+            txids: HashSet::new(),
+        }
+    }
+
+    /// Add arbitrary element to a filter
+    pub fn add_element(&mut self, data: &[u8]) {
+        if !data.is_empty() && !self.elements.contains(data) {
+            self.elements.insert(data.to_vec());
+        }
+    }
+
+    /// Add output and spent scripts of the transaction
+    pub fn add_transaction<F>(&mut self, tx: &Transaction, script_for_coin: F) -> Result<(), Error>
+    where
+        F: Fn(&OutPoint) -> Result<Script, Error>,
+    {
+        self.add_outputs(tx);
+        self.add_inputs(tx, script_for_coin)
+    }
+
+    /// Add output scripts of the transaction
+    pub fn add_outputs(&mut self, tx: &Transaction) {
+        let policy = self.policy;
+        add_tx_output_scripts(self, policy, tx);
+        self.txids.insert(tx.txid());
+    }
+
+    /// Add spent scripts of the transaction. Inputs of a coinbase are skipped.
+    pub fn add_inputs<F>(&mut self, tx: &Transaction, script_for_coin: F) -> Result<(), Error>
+    where
+        F: Fn(&OutPoint) -> Result<Script, Error>,
+    {
+        if !tx.is_coin_base() {
+            let policy = self.policy;
+            add_tx_input_scripts(self, policy, tx, script_for_coin)?;
+        }
+        Ok(())
+    }
+
+    /// Add all transactions from the iterator, owned or borrowed
+    pub fn add_transactions<I, T, F>(&mut self, txs: I, script_for_coin: F) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Borrow<Transaction>,
+        F: Fn(&OutPoint) -> Result<Script, Error>,
+    {
+        for tx in txs {
+            self.add_transaction(tx.borrow(), &script_for_coin)?;
+        }
+        Ok(())
+    }
+
+    /// Add output scripts of the transaction and queue its inputs for `resolve_pending`
+    pub fn defer_transaction(&mut self, tx: &Transaction) {
+        self.add_outputs(tx);
+        let policy = self.policy;
+        if !tx.is_coin_base() {
+            self.pending.extend(
+                tx.input
+                    .iter()
+                    .filter(|i| policy.is_input_indexable(i))
+                    .map(|i| i.previous_output),
+            );
+        }
+    }
+
+    /// Outpoints queued by `defer_transaction` that are not resolved yet. Some of them can
+    /// be dropped by `resolve_pending` later if their transactions are added.
+    pub fn pending_outpoints(&self) -> &[OutPoint] {
+        &self.pending
+    }
+
+    /// Resolve all queued outpoints with one call and add their scripts.
+    ///
+    /// The resolver must return exactly one entry per outpoint in the order of the given
+    /// outpoints, `None` for an unknown one, which is handled by the `MissingUtxoPolicy`. A result
+    /// of another length fails. Outpoints created by any added transaction are not passed to it.
+    pub fn resolve_pending<F>(&mut self, resolver: F) -> Result<(), Error>
+    where
+        F: FnOnce(&[OutPoint]) -> Result<Vec<Option<Script>>, Error>,
+    {
+        let txids = &self.txids;
+        self.pending.retain(|o| !txids.contains(&o.txid));
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let scripts = resolver(&self.pending)?;
+        if scripts.len() != self.pending.len() {
+            return Err(Error::Io(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "resolver must return one script per outpoint",
+            )));
+        }
+        let pending = std::mem::take(&mut self.pending);
+        for (outpoint, script) in pending.iter().zip(scripts) {
+            match script {
+                Some(script) => {
+                    if self.policy.is_script_indexable(&script) {
+                        self.add_element(script.as_bytes());
+                    }
+                }
+                None => self.missing.handle(
+                    outpoint,
+                    Error::UtxoMissing(*outpoint),
+                    &mut self.skipped,
+                )?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Outpoints of inputs left out of the filter with `MissingUtxoPolicy::Collect`
+    pub fn skipped(&self) -> &[OutPoint] {
+        &self.skipped
+    }
+
+    /// Amount of distinct elements added so far
+    pub fn len(&self) -> usize {
+        self.elements.len()
+    }
+
+    /// Check whether no elements were added yet
+    pub fn is_empty(&self) -> bool {
+        self.elements.is_empty()
+    }
+
+    /// Encode collected elements into Golomb coded content of a filter
+    pub fn finish(self) -> Result<Vec<u8>, Error> {
+        let mut out = Vec::new();
+        gcs::write_filter(&mut out, self.k0, self.k1, self.params, &self.elements)?;
+        Ok(out)
+    }
+}
+
+/// Builds an outpoint filter with ids of added transactions and outpoints spent by them, see
+/// `util::add_tx_outpoints` and `FilterId::OUTPOINTS`.
+pub struct OutpointFilterBuilder {
+    k0: u64,
+    k1: u64,
+    params: GcsParams,
+    elements: HashSet<Vec<u8>>,
+}
+
+impl FilterWriter for OutpointFilterBuilder {
+    fn add_filter_element(&mut self, data: &[u8]) {
+        if !data.is_empty() && !self.elements.contains(data) {
+            self.elements.insert(data.to_vec());
+        }
+    }
+}
+
+impl OutpointFilterBuilder {
+    /// Create a builder for the outpoint filter of the block with given hash
+    pub fn for_block(block_hash: &BlockHash) -> OutpointFilterBuilder {
+        let (k0, k1) = block_filter_keys(block_hash);
+        OutpointFilterBuilder {
+            k0,
+            k1,
+            params: GcsParams::BIP158,
+            elements: HashSet::new(),
+        }
+    }
+
+    /// Create a builder for a mempool outpoint filter with given SipHash keys and coding parameters
+    pub fn for_mempool(key: &MempoolFilterKey, params: GcsParams) -> OutpointFilterBuilder {
+        OutpointFilterBuilder {
+            k0: key.k0(),
+            k1: key.k1(),
+            params,
+            elements: HashSet::new(),
+        }
+    }
+
+    /// Add the txid and spent outpoints of the transaction
+    pub fn add_transaction(&mut self, tx: &Transaction) {
+        add_tx_outpoints(self, tx);
+    }
+
+    /// Amount of distinct elements added so far
+    pub fn len(&self) -> usize {
+        self.elements.len()
+    }
+
+    /// Check whether no elements were added yet
+    pub fn is_empty(&self) -> bool {
+        self.elements.is_empty()
+    }
+
+    /// Encode collected elements into Golomb coded content of a filter
+    pub fn finish(self) -> Result<Vec<u8>, Error> {
+        let mut out = Vec::new();
+        gcs::write_filter(&mut out, self.k0, self.k1, self.params, &self.elements)?;
+        Ok(out)
+    }
+}
@@ -0,0 +1,177 @@
+use crate::btc::ErgveinFilter;
+use crate::builder::FilterBuilder;
+use crate::policy::{FilterVersion, MissingUtxoPolicy, ScriptPolicy};
+use crate::test::utils::*;
+use bitcoin::util::bip158::Error;
+use bitcoin::{OutPoint, Transaction, TxIn};
+use std::cell::Cell;
+
+#[test]
+fn streaming_block_filter() {
+    let block = load_block("./test/block1");
+    let txmap = block1_inputs();
+    let script_for_coin = script_for_coin(&txmap);
+    let expected = ErgveinFilter::new_script_filter(
+        &block,
+        &FilterVersion::V0,
+        MissingUtxoPolicy::Fail,
+        script_for_coin,
+    )
+    .unwrap()
+    .0;
+
+    let mut builder = FilterBuilder::for_block(
+        &block.block_hash(),
+        &FilterVersion::V0,
+        MissingUtxoPolicy::Fail,
+    );
+    for tx in block.txdata.clone() {
+        builder.add_transaction(&tx, script_for_coin).unwrap();
+    }
+    // Adding the same transactions again doesn't change the element set
+    builder
+        .add_transactions(block.txdata.iter(), script_for_coin)
+        .unwrap();
+    assert!(!builder.is_empty());
+    assert_eq!(builder.finish().unwrap(), expected.content);
+}
+
+#[test]
+fn batch_block_filter() {
+    let block = load_block("./test/block1");
+    let txmap = block1_inputs();
+    let script_for_coin = script_for_coin(&txmap);
+    let expected = ErgveinFilter::new_script_filter(
+        &block,
+        &FilterVersion::V0,
+        MissingUtxoPolicy::Fail,
+        script_for_coin,
+    )
+    .unwrap()
+    .0;
+
+    let calls = Cell::new(0);
+    let (filter, skipped) = ErgveinFilter::new_script_filter_batch(
+        &block,
+        &FilterVersion::V0,
+        MissingUtxoPolicy::Fail,
+        |outs| {
+            calls.set(calls.get() + 1);
+            Ok(outs.iter().map(|o| txmap.get(o).cloned()).collect())
+        },
+    )
+    .unwrap();
+    assert_eq!(calls.get(), 1);
+    assert_eq!(filter, expected);
+    assert!(skipped.is_empty());
+
+    // the first resolved outpoint is unknown to the resolver
+    let first = block.txdata[1..]
+        .iter()
+        .flat_map(|tx| tx.input.iter())
+        .find(|i| FilterVersion::V0.is_input_indexable(i))
+        .unwrap()
+        .previous_output;
+    let resolver = |outs: &[OutPoint]| {
+        Ok(outs
+            .iter()
+            .map(|o| txmap.get(o).cloned().filter(|_| *o != first))
+            .collect())
+    };
+    let (_, skipped) = ErgveinFilter::new_script_filter_batch(
+        &block,
+        &FilterVersion::V0,
+        MissingUtxoPolicy::Collect,
+        resolver,
+    )
+    .unwrap();
+    assert_eq!(skipped, vec![first]);
+    assert_eq!(
+        ErgveinFilter::new_script_filter_batch(
+            &block,
+            &FilterVersion::V0,
+            MissingUtxoPolicy::Fail,
+            resolver,
+        )
+        .unwrap_err()
+        .to_string(),
+        Error::UtxoMissing(first).to_string()
+    );
+
+    let mut builder = FilterBuilder::for_block(
+        &block.block_hash(),
+        &FilterVersion::V0,
+        MissingUtxoPolicy::Skip,
+    );
+    let mut txs = block.txdata.iter();
+    while builder.pending_outpoints().is_empty() {
+        builder.defer_transaction(txs.next().unwrap());
+    }
+    // a result of another length fails even when missing scripts are skipped
+    assert!(matches!(
+        builder.resolve_pending(|_| Ok(vec![])),
+        Err(Error::Io(_))
+    ));
+    let n_pending = builder.pending_outpoints().len();
+    assert!(matches!(
+        builder.resolve_pending(|outs| Ok(vec![None; outs.len() + 1])),
+        Err(Error::Io(_))
+    ));
+    assert_eq!(builder.pending_outpoints().len(), n_pending);
+}
+
+#[test]
+fn intra_block_spends() {
+    let mut block = load_block("./test/block1");
+    let txmap = block1_inputs();
+    let script_for_coin = script_for_coin(&txmap);
+    let expected = ErgveinFilter::new_script_filter(
+        &block,
+        &FilterVersion::V0,
+        MissingUtxoPolicy::Fail,
+        script_for_coin,
+    )
+    .unwrap()
+    .0;
+
+    let parent = block.txdata[1].txid();
+    block.txdata.push(Transaction {
+        version: 2,
+        lock_time: 0,
+        input: vec![TxIn {
+            previous_output: OutPoint::new(parent, 0),
+            script_sig: Default::default(),
+            sequence: 0xffffffff,
+            witness: vec![vec![1]],
+        }],
+        output: vec![],
+    });
+    let mut builder = FilterBuilder::for_block(
+        &block.block_hash(),
+        &FilterVersion::V0,
+        MissingUtxoPolicy::Fail,
+    );
+    builder
+        .add_transactions(&block.txdata, |o: &OutPoint| {
+            assert_ne!(o.txid, parent, "Lookup of intra-block outpoint");
+            script_for_coin(o)
+        })
+        .unwrap();
+    assert_eq!(builder.finish().unwrap(), expected.content);
+
+    let mut builder = FilterBuilder::for_block(
+        &block.block_hash(),
+        &FilterVersion::V0,
+        MissingUtxoPolicy::Fail,
+    );
+    for tx in &block.txdata {
+        builder.defer_transaction(tx);
+    }
+    builder
+        .resolve_pending(|outs| {
+            assert!(outs.iter().all(|o| o.txid != parent));
+            Ok(outs.iter().map(|o| txmap.get(o).cloned()).collect())
+        })
+        .unwrap();
+    assert_eq!(builder.finish().unwrap(), expected.content);
+}
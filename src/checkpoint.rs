@@ -0,0 +1,237 @@
+use crate::btc::ErgveinFilter;
+use crate::header::FilterHeaderChain;
+use crate::policy::FilterId;
+use bitcoin::network::message_filter::CFCheckpt;
+use bitcoin::{BlockHash, FilterHash, FilterHeader};
+use std::borrow::Borrow;
+use std::error;
+use std::fmt;
+use std::ops::RangeInclusive;
+
+#[cfg(test)]
+mod test;
+
+/// Distance in blocks between checkpoints, as in BIP157 `cfcheckpt`
+pub const CHECKPOINT_INTERVAL: u32 = 1000;
+
+/// Downloaded headers don't lead to the checkpoint at the end of the interval
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    /// Heights of blocks one of which has a wrong filter or header
+    pub interval: RangeInclusive<u32>,
+    /// Checkpoint at the end of the interval
+    pub expected: FilterHeader,
+    /// Header at the end of the interval computed from the downloaded ones
+    pub actual: FilterHeader,
+}
+
+impl error::Error for Divergence {}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "filter headers diverge in blocks {}..={}: expected {}, got {}",
+            self.interval.start(),
+            self.interval.end(),
+            self.expected,
+            self.actual
+        )
+    }
+}
+
+/// Filter headers of every `CHECKPOINT_INTERVAL`th block starting from genesis.
+///
+/// The first checkpoint is the header at height 1000, the i-th is at `(i + 1) * 1000`. Checkpoints
+/// let a client download intervals between them in parallel and detect a server that serves
+/// wrong filters without downloading the whole chain from another one.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Checkpoints {
+    headers: Vec<FilterHeader>,
+}
+
+impl Checkpoints {
+    /// Wrap already known checkpoints, e.g. received in a `cfcheckpt` message
+    pub fn new(headers: Vec<FilterHeader>) -> Checkpoints {
+        Checkpoints { headers }
+    }
+
+    /// Compute checkpoints from filter headers of all blocks starting from genesis
+    pub fn from_headers<I>(headers: I) -> Checkpoints
+    where
+        I: IntoIterator<Item = FilterHeader>,
+    {
+        let headers = headers
+            .into_iter()
+            .enumerate()
+            .filter(|(height, _)| is_checkpoint_height(*height as u32))
+            .map(|(_, header)| header)
+            .collect();
+        Checkpoints { headers }
+    }
+
+    /// Compute checkpoints from filter hashes of all blocks starting from genesis
+    pub fn from_hashes<I>(hashes: I) -> Checkpoints
+    where
+        I: IntoIterator<Item = FilterHash>,
+    {
+        let mut prev = FilterHeader::default();
+        Checkpoints::from_headers(hashes.into_iter().map(|hash| {
+            prev = hash.filter_header(&prev);
+            prev
+        }))
+    }
+
+    /// Compute checkpoints from filters of all blocks starting from genesis
+    pub fn from_filters<I, F>(filters: I) -> Checkpoints
+    where
+        I: IntoIterator<Item = F>,
+        F: Borrow<ErgveinFilter>,
+    {
+        Checkpoints::from_hashes(filters.into_iter().map(|f| f.borrow().filter_hash()))
+    }
+
+    /// Collect checkpoints from a chain that starts at genesis, collecting stops at the first
+    /// checkpoint height the chain doesn't have
+    pub fn from_chain(chain: &FilterHeaderChain) -> Checkpoints {
+        let headers = (1..)
+            .map(|i| chain.header(i * CHECKPOINT_INTERVAL))
+            .take_while(|h| h.is_some())
+            .flatten()
+            .collect();
+        Checkpoints { headers }
+    }
+
+    /// Take checkpoints of a `cfcheckpt` message
+    pub fn from_message(message: &CFCheckpt) -> Checkpoints {
+        Checkpoints::new(message.filter_headers.clone())
+    }
+
+    /// Make a `cfcheckpt` message for filters of the kind up to the block
+    pub fn to_message(&self, id: FilterId, stop_hash: BlockHash) -> CFCheckpt {
+        CFCheckpt {
+            filter_type: id.filter_type,
+            stop_hash,
+            filter_headers: self.headers.clone(),
+        }
+    }
+
+    /// All checkpoints in order of height
+    pub fn headers(&self) -> &[FilterHeader] {
+        &self.headers
+    }
+
+    /// Amount of checkpoints
+    pub fn len(&self) -> usize {
+        self.headers.len()
+    }
+
+    /// Check whether there are no checkpoints, i.e. the chain is shorter than the interval
+    pub fn is_empty(&self) -> bool {
+        self.headers.is_empty()
+    }
+
+    /// Height of the last checkpoint
+    pub fn last_height(&self) -> Option<u32> {
+        (!self.headers.is_empty()).then(|| self.headers.len() as u32 * CHECKPOINT_INTERVAL)
+    }
+
+    /// Checkpoint at the height, `None` if the height is not a checkpoint one or is not known
+    pub fn get(&self, height: u32) -> Option<FilterHeader> {
+        if is_checkpoint_height(height) {
+            self.headers
+                .get((height / CHECKPOINT_INTERVAL - 1) as usize)
+                .copied()
+        } else {
+            None
+        }
+    }
+
+    /// Find the first interval where the checkpoints differ, e.g. received from two servers.
+    ///
+    /// Checkpoints one of the lists doesn't have yet are not compared.
+    pub fn first_divergence(&self, other: &Checkpoints) -> Option<RangeInclusive<u32>> {
+        self.headers
+            .iter()
+            .zip(other.headers.iter())
+            .position(|(a, b)| a != b)
+            .map(|i| interval((i as u32 + 1) * CHECKPOINT_INTERVAL))
+    }
+
+    /// Validate downloaded headers, `headers[i]` is at height `start_height + i`.
+    ///
+    /// Every checkpoint within the range must match, headers after the last checkpoint are not
+    /// checked.
+    pub fn validate_headers(
+        &self,
+        start_height: u32,
+        headers: &[FilterHeader],
+    ) -> Result<(), Divergence> {
+        for (height, actual) in (start_height..).zip(headers.iter()) {
+            self.validate_header(height, actual)?;
+        }
+        Ok(())
+    }
+
+    /// Validate headers downloaded as in a `cfheaders` message: the header preceding the range
+    /// at `start_height` and filter hashes of the range.
+    pub fn validate_hashes(
+        &self,
+        start_height: u32,
+        previous_filter_header: &FilterHeader,
+        filter_hashes: &[FilterHash],
+    ) -> Result<(), Divergence> {
+        if let Some(height) = start_height.checked_sub(1) {
+            self.validate_header(height, previous_filter_header)?;
+        }
+        let mut prev = *previous_filter_header;
+        for (height, hash) in (start_height..).zip(filter_hashes.iter()) {
+            prev = hash.filter_header(&prev);
+            self.validate_header(height, &prev)?;
+        }
+        Ok(())
+    }
+
+    /// Validate all headers of the chain
+    pub fn validate_chain(&self, chain: &FilterHeaderChain) -> Result<(), Divergence> {
+        let tip_height = match chain.tip_height() {
+            Some(height) => height,
+            None => return Ok(()),
+        };
+        for i in 1..=self.headers.len() as u32 {
+            let height = i * CHECKPOINT_INTERVAL;
+            if height > tip_height {
+                break;
+            }
+            if let Some(actual) = chain.header(height) {
+                self.validate_header(height, &actual)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_header(&self, height: u32, actual: &FilterHeader) -> Result<(), Divergence> {
+        match self.get(height) {
+            Some(expected) if expected != *actual => Err(Divergence {
+                interval: interval(height),
+                expected,
+                actual: *actual,
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Check whether the block at the height has a checkpoint
+pub fn is_checkpoint_height(height: u32) -> bool {
+    height > 0 && height % CHECKPOINT_INTERVAL == 0
+}
+
+/// Blocks whose filters lead to the checkpoint at the height from the previous one
+fn interval(height: u32) -> RangeInclusive<u32> {
+    if height == CHECKPOINT_INTERVAL {
+        0..=height
+    } else {
+        height + 1 - CHECKPOINT_INTERVAL..=height
+    }
+}
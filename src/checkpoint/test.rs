@@ -0,0 +1,122 @@
+use crate::btc::ErgveinFilter;
+use crate::checkpoint::{Checkpoints, CHECKPOINT_INTERVAL};
+use crate::header::FilterHeaderChain;
+use crate::policy::{FilterVersion, ScriptPolicy, ERGVEIN_FILTER_TYPE};
+use bitcoin::{BlockHash, FilterHeader};
+
+fn make_filters(n: u32) -> Vec<ErgveinFilter> {
+    (0..n)
+        .map(|i| {
+            let mut content = vec![1];
+            content.extend_from_slice(&i.to_le_bytes());
+            ErgveinFilter::new(&content)
+        })
+        .collect()
+}
+
+fn make_headers(filters: &[ErgveinFilter]) -> Vec<FilterHeader> {
+    let mut chain = FilterHeaderChain::new();
+    filters.iter().map(|f| chain.push(f)).collect()
+}
+
+#[test]
+fn generate_checkpoints() {
+    let filters = make_filters(3500);
+    let headers = make_headers(&filters);
+    let checkpoints = Checkpoints::from_filters(&filters);
+    assert_eq!(checkpoints.len(), 3);
+    assert_eq!(checkpoints.last_height(), Some(3000));
+    assert_eq!(checkpoints.get(2000), Some(headers[2000]));
+    assert_eq!(checkpoints.get(2001), None);
+    assert_eq!(checkpoints.get(0), None);
+    assert_eq!(checkpoints.get(4000), None);
+    assert_eq!(checkpoints, Checkpoints::from_headers(headers.clone()));
+    assert_eq!(
+        checkpoints,
+        Checkpoints::from_hashes(filters.iter().map(|f| f.filter_hash()))
+    );
+
+    let mut chain = FilterHeaderChain::new();
+    for filter in &filters {
+        chain.push(filter);
+    }
+    assert_eq!(checkpoints, Checkpoints::from_chain(&chain));
+    assert!(Checkpoints::from_filters(&filters[..999]).is_empty());
+
+    let message = checkpoints.to_message(FilterVersion::V0.id(), BlockHash::default());
+    assert_eq!(message.filter_type, ERGVEIN_FILTER_TYPE);
+    assert_eq!(checkpoints, Checkpoints::from_message(&message));
+}
+
+#[test]
+fn validate_ranges() {
+    let filters = make_filters(3500);
+    let headers = make_headers(&filters);
+    let checkpoints = Checkpoints::from_headers(headers.clone());
+
+    assert_eq!(checkpoints.validate_headers(0, &headers), Ok(()));
+    assert_eq!(
+        checkpoints.validate_headers(1500, &headers[1500..3200]),
+        Ok(())
+    );
+    let hashes: Vec<_> = filters[1500..3200]
+        .iter()
+        .map(|f| f.filter_hash())
+        .collect();
+    assert_eq!(
+        checkpoints.validate_hashes(1500, &headers[1499], &hashes),
+        Ok(())
+    );
+
+    // a server lies about the filter at height 2345
+    let mut bad_filters = filters.clone();
+    bad_filters[2345] = ErgveinFilter::new(&[0]);
+    let bad_headers = make_headers(&bad_filters);
+    let divergence = checkpoints
+        .validate_headers(1500, &bad_headers[1500..])
+        .unwrap_err();
+    assert_eq!(divergence.interval, 2001..=3000);
+    assert_eq!(divergence.expected, headers[3000]);
+    assert_eq!(divergence.actual, bad_headers[3000]);
+
+    let bad_hashes: Vec<_> = bad_filters[1500..]
+        .iter()
+        .map(|f| f.filter_hash())
+        .collect();
+    let divergence = checkpoints
+        .validate_hashes(1500, &headers[1499], &bad_hashes)
+        .unwrap_err();
+    assert_eq!(divergence.interval, 2001..=3000);
+
+    // the wrong header is not at a checkpoint height yet
+    assert_eq!(
+        checkpoints.validate_headers(2001, &bad_headers[2001..2999]),
+        Ok(())
+    );
+
+    let mut chain = FilterHeaderChain::new();
+    for filter in &bad_filters[..500] {
+        chain.push(filter);
+    }
+    assert_eq!(checkpoints.validate_chain(&chain), Ok(()));
+    chain.push_hash(bad_filters[500].filter_hash());
+    for filter in &bad_filters[501..] {
+        chain.push(filter);
+    }
+    assert_eq!(
+        checkpoints.validate_chain(&chain).unwrap_err().interval,
+        2001..=3000
+    );
+
+    let bad_checkpoints = Checkpoints::from_headers(bad_headers);
+    assert_eq!(checkpoints.first_divergence(&checkpoints), None);
+    assert_eq!(
+        checkpoints.first_divergence(&bad_checkpoints),
+        Some(2001..=3000)
+    );
+    let genesis_lie = Checkpoints::new(vec![FilterHeader::default()]);
+    assert_eq!(
+        checkpoints.first_divergence(&genesis_lie),
+        Some(0..=CHECKPOINT_INTERVAL)
+    );
+}
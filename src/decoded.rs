@@ -0,0 +1,109 @@
+use crate::btc::ErgveinFilter;
+use crate::gcs::{self, GcsFilterReader};
+use crate::mempool::{ErgveinMempoolFilter, MempoolFilterKey};
+use crate::util::{block_filter_keys, GcsParams};
+use bitcoin::util::bip158::Error;
+use bitcoin::BlockHash;
+
+#[cfg(test)]
+mod test;
+
+/// A filter with its hashed elements decoded once into a sorted vector.
+///
+/// Golomb-Rice coded filters take about `p + 2` bits per element, the decoded form takes 64 bits
+/// per element, so it is roughly 3 times larger for BIP158 parameters. In exchange each query
+/// element is looked up by binary search without reading the whole filter, which pays off when
+/// the same filter is queried with several watch lists.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodedFilter {
+    k0: u64,
+    k1: u64,
+    nm: u64,
+    values: Vec<u64>,
+}
+
+impl DecodedFilter {
+    /// Decode a block filter, the block hash is the key of the filter
+    pub fn from_block_filter(
+        filter: &ErgveinFilter,
+        block_hash: &BlockHash,
+    ) -> Result<DecodedFilter, Error> {
+        let (k0, k1) = block_filter_keys(block_hash);
+        DecodedFilter::decode(&filter.content, k0, k1, GcsParams::BIP158)
+    }
+
+    /// Decode a mempool filter built with the key
+    pub fn from_mempool_filter(
+        filter: &ErgveinMempoolFilter,
+        key: &MempoolFilterKey,
+    ) -> Result<DecodedFilter, Error> {
+        DecodedFilter::decode(&filter.content, key.k0(), key.k1(), filter.params)
+    }
+
+    fn decode(content: &[u8], k0: u64, k1: u64, params: GcsParams) -> Result<Self, Error> {
+        let reader = GcsFilterReader::new(k0, k1, params);
+        let (n_elements, values) = reader.decode(content)?;
+        Ok(DecodedFilter {
+            k0,
+            k1,
+            nm: reader.range(n_elements)?,
+            values,
+        })
+    }
+
+    /// Amount of elements in the filter
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    /// Check whether the filter has no elements
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Sorted hashed values of the filter elements
+    pub fn values(&self) -> &[u64] {
+        &self.values
+    }
+
+    fn map(&self, element: &[u8]) -> u64 {
+        gcs::map_to_range(gcs::hash_element(self.k0, self.k1, element), self.nm)
+    }
+
+    /// Check whether the element is in the filter
+    pub fn contains(&self, element: &[u8]) -> bool {
+        self.values.binary_search(&self.map(element)).is_ok()
+    }
+
+    /// match any query pattern, an empty query matches as in `ErgveinFilter::match_any`
+    pub fn match_any(&self, query: &mut dyn Iterator<Item = &[u8]>) -> bool {
+        let mut is_empty = true;
+        for e in query {
+            if self.contains(e) {
+                return true;
+            }
+            is_empty = false;
+        }
+        is_empty
+    }
+
+    /// match all query pattern
+    pub fn match_all(&self, query: &mut dyn Iterator<Item = &[u8]>) -> bool {
+        for e in query {
+            if !self.contains(e) {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Find which query elements are in the filter, returns their indices in ascending order
+    pub fn match_set<T: AsRef<[u8]>>(&self, query: &[T]) -> Vec<usize> {
+        query
+            .iter()
+            .enumerate()
+            .filter(|(_, e)| self.contains(e.as_ref()))
+            .map(|(i, _)| i)
+            .collect()
+    }
+}
@@ -0,0 +1,96 @@
+use crate::btc::ErgveinFilter;
+use crate::decoded::DecodedFilter;
+use crate::mempool::{ErgveinMempoolFilter, MempoolFilterKey};
+use crate::policy::{FilterVersion, MissingUtxoPolicy};
+use crate::test::utils::*;
+use crate::util::GcsParams;
+
+#[test]
+fn decoded_block_filter() {
+    let block = load_block("./test/block1");
+    let txmap = block1_inputs();
+    let script_for_coin = script_for_coin(&txmap);
+    let (filter, _) = ErgveinFilter::new_script_filter(
+        &block,
+        &FilterVersion::V0,
+        MissingUtxoPolicy::Fail,
+        script_for_coin,
+    )
+    .unwrap();
+    let block_hash = block.block_hash();
+    let decoded = DecodedFilter::from_block_filter(&filter, &block_hash).unwrap();
+    assert_eq!(decoded.len() as u64, filter.n_elements());
+    assert!(decoded.values().windows(2).all(|w| w[0] <= w[1]));
+
+    let mut query: Vec<Vec<u8>> = vec![b"not in the filter".to_vec()];
+    for tx in &block.txdata {
+        for o in &tx.output {
+            query.push(o.script_pubkey.to_bytes());
+        }
+    }
+    assert_eq!(
+        filter.match_set(&block_hash, &query).unwrap(),
+        decoded.match_set(&query)
+    );
+    for e in &query {
+        let e = e.as_slice();
+        assert_eq!(
+            filter
+                .match_any(&block_hash, &mut std::iter::once(e))
+                .unwrap(),
+            decoded.contains(e)
+        );
+    }
+    assert!(decoded.match_any(&mut query.iter().map(|e| e.as_slice())));
+    assert!(!decoded.match_all(&mut query.iter().map(|e| e.as_slice())));
+    assert!(decoded.match_all(&mut std::iter::empty()));
+    assert_eq!(
+        filter
+            .match_any(&block_hash, &mut std::iter::empty())
+            .unwrap(),
+        decoded.match_any(&mut std::iter::empty())
+    );
+
+    let empty_filter = ErgveinFilter::new(&[0]);
+    let empty = DecodedFilter::from_block_filter(&empty_filter, &block_hash).unwrap();
+    assert!(empty.is_empty());
+    assert!(!empty.contains(b"anything"));
+    assert_eq!(
+        empty_filter
+            .match_any(&block_hash, &mut std::iter::empty())
+            .unwrap(),
+        empty.match_any(&mut std::iter::empty())
+    );
+}
+
+#[test]
+fn decoded_mempool_filter() {
+    let key = MempoolFilterKey::from_bytes(*b"qwertyuiopasdfgh");
+    let params = GcsParams { p: 10, m: 1024 };
+    let block = load_block("./test/block1");
+    let txmap = block1_inputs();
+    let mut txs = block.txdata;
+    txs.remove(0);
+    let (filter, _) = ErgveinMempoolFilter::new_script_filter(
+        &key,
+        params,
+        &FilterVersion::V0,
+        MissingUtxoPolicy::Fail,
+        txs.clone(),
+        script_for_coin(&txmap),
+    )
+    .unwrap();
+    let decoded = DecodedFilter::from_mempool_filter(&filter, &key).unwrap();
+    assert_eq!(decoded.len() as u64, filter.n_elements());
+    for tx in &txs {
+        let scripts: Vec<_> = tx
+            .output
+            .iter()
+            .map(|o| o.script_pubkey.as_bytes())
+            .collect();
+        assert_eq!(
+            filter.match_set(&key, &scripts).unwrap(),
+            decoded.match_set(&scripts)
+        );
+    }
+}
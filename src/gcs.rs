@@ -0,0 +1,479 @@
+//! Golomb-Rice coded sets as specified in BIP158.
+//!
+//! The encoding is byte compatible with `bitcoin::util::bip158`, but the reader also exposes the
+//! element count and the decoded values of a filter. Matching reads the filter content in place,
+//! checking a single element doesn't allocate.
+use crate::util::GcsParams;
+use bitcoin::consensus::{Decodable, Encodable};
+use bitcoin::hashes::siphash24;
+use bitcoin::util::bip158::Error;
+use bitcoin::VarInt;
+use std::cmp;
+use std::collections::HashSet;
+use std::io;
+
+#[cfg(test)]
+mod test;
+
+/// Hash an element with siphash keys of a filter
+pub fn hash_element(k0: u64, k1: u64, element: &[u8]) -> u64 {
+    siphash24::Hash::hash_to_u64_with_keys(k0, k1, element)
+}
+
+/// Fast reduction of a hash to `[0, nm)` range
+pub fn map_to_range(hash: u64, nm: u64) -> u64 {
+    ((hash as u128 * nm as u128) >> 64) as u64
+}
+
+/// Golomb-Rice encode a number to a bit stream
+pub fn golomb_rice_encode<W: io::Write>(
+    writer: &mut BitStreamWriter<W>,
+    n: u64,
+    p: u8,
+) -> Result<usize, io::Error> {
+    check_p(p)?;
+    let mut wrote = 0;
+    let mut q = n >> p;
+    while q > 0 {
+        let nbits = cmp::min(q, 64);
+        wrote += writer.write(!0u64, nbits as u8)?;
+        q -= nbits;
+    }
+    wrote += writer.write(0, 1)?;
+    wrote += writer.write(n, p)?;
+    Ok(wrote)
+}
+
+/// Golomb-Rice decode a number from a bit stream
+pub fn golomb_rice_decode<R: io::Read>(
+    reader: &mut BitStreamReader<R>,
+    p: u8,
+) -> Result<u64, io::Error> {
+    check_p(p)?;
+    let mut q = 0u64;
+    while reader.read(1)? == 1 {
+        q += 1;
+    }
+    let r = reader.read(p)?;
+    q.checked_mul(1 << p)
+        .map(|n| n + r)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Golomb-Rice code overflows"))
+}
+
+fn check_p(p: u8) -> Result<(), io::Error> {
+    if p >= 64 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Golomb-Rice parameter is too large",
+        ));
+    }
+    Ok(())
+}
+
+/// Bitwise stream reader
+pub struct BitStreamReader<R> {
+    buffer: u8,
+    offset: u8,
+    reader: R,
+}
+
+impl<R: io::Read> BitStreamReader<R> {
+    /// Create a new reader that reads bitwise from a given reader
+    pub fn new(reader: R) -> BitStreamReader<R> {
+        BitStreamReader {
+            buffer: 0,
+            offset: 8,
+            reader,
+        }
+    }
+
+    /// Read nbits bits, most significant first
+    pub fn read(&mut self, mut nbits: u8) -> Result<u64, io::Error> {
+        if nbits > 64 {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "can not read more than 64 bits at once",
+            ));
+        }
+        let mut data = 0u64;
+        while nbits > 0 {
+            if self.offset == 8 {
+                let mut byte = [0u8];
+                self.reader.read_exact(&mut byte)?;
+                self.buffer = byte[0];
+                self.offset = 0;
+            }
+            let bits = cmp::min(8 - self.offset, nbits);
+            data <<= bits;
+            data |= ((self.buffer << self.offset) >> (8 - bits)) as u64;
+            self.offset += bits;
+            nbits -= bits;
+        }
+        Ok(data)
+    }
+}
+
+/// Bitwise stream writer
+pub struct BitStreamWriter<W> {
+    buffer: u8,
+    offset: u8,
+    writer: W,
+}
+
+impl<W: io::Write> BitStreamWriter<W> {
+    /// Create a new writer that writes bitwise to a given writer
+    pub fn new(writer: W) -> BitStreamWriter<W> {
+        BitStreamWriter {
+            buffer: 0,
+            offset: 0,
+            writer,
+        }
+    }
+
+    /// Write nbits lowest bits of data, most significant first
+    pub fn write(&mut self, data: u64, mut nbits: u8) -> Result<usize, io::Error> {
+        if nbits > 64 {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "can not write more than 64 bits at once",
+            ));
+        }
+        let mut wrote = 0;
+        while nbits > 0 {
+            let bits = cmp::min(8 - self.offset, nbits);
+            self.buffer |= ((data << (64 - nbits)) >> (64 - 8 + self.offset)) as u8;
+            self.offset += bits;
+            nbits -= bits;
+            if self.offset == 8 {
+                wrote += self.flush()?;
+            }
+        }
+        Ok(wrote)
+    }
+
+    /// Flush bits not yet written, padding the last byte with zeros
+    pub fn flush(&mut self) -> Result<usize, io::Error> {
+        if self.offset > 0 {
+            self.writer.write_all(&[self.buffer])?;
+            self.buffer = 0;
+            self.offset = 0;
+            Ok(1)
+        } else {
+            Ok(0)
+        }
+    }
+}
+
+/// Write already hashed and sorted values as a filter
+pub fn write_values<W: io::Write>(
+    mut writer: W,
+    values: &[u64],
+    p: u8,
+) -> Result<usize, io::Error> {
+    let mut wrote = VarInt(values.len() as u64).consensus_encode(&mut writer)?;
+    let mut writer = BitStreamWriter::new(writer);
+    let mut last = 0;
+    for &data in values {
+        wrote += golomb_rice_encode(&mut writer, data - last, p)?;
+        last = data;
+    }
+    wrote += writer.flush()?;
+    Ok(wrote)
+}
+
+/// Hash distinct elements with siphash keys of a filter and write them as the filter
+pub fn write_filter<W, T>(
+    writer: W,
+    k0: u64,
+    k1: u64,
+    params: GcsParams,
+    elements: &HashSet<T>,
+) -> Result<usize, io::Error>
+where
+    W: io::Write,
+    T: AsRef<[u8]>,
+{
+    let mut hashes: Vec<_> = elements
+        .iter()
+        .map(|e| hash_element(k0, k1, e.as_ref()))
+        .collect();
+    hashes.sort_unstable();
+    write_hashes(writer, params, &hashes)
+}
+
+/// Write sorted siphash values of distinct elements as the filter. Mapping to the range keeps
+/// the order, so the values are not sorted again.
+pub fn write_hashes<W: io::Write>(
+    writer: W,
+    params: GcsParams,
+    hashes: &[u64],
+) -> Result<usize, io::Error> {
+    let nm = (hashes.len() as u64)
+        .checked_mul(params.m)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "too many elements"))?;
+    let mapped: Vec<_> = hashes.iter().map(|h| map_to_range(*h, nm)).collect();
+    write_values(writer, &mapped, params.p)
+}
+
+/// Golomb-Rice coded set writer
+pub struct GcsFilterWriter<W> {
+    writer: W,
+    k0: u64,
+    k1: u64,
+    params: GcsParams,
+    elements: HashSet<Vec<u8>>,
+}
+
+impl<W: io::Write> GcsFilterWriter<W> {
+    /// Create a new writer with siphash keys of the filter
+    pub fn new(writer: W, k0: u64, k1: u64, params: GcsParams) -> GcsFilterWriter<W> {
+        GcsFilterWriter {
+            writer,
+            k0,
+            k1,
+            params,
+            elements: HashSet::new(),
+        }
+    }
+
+    /// Add some data to the filter, empty elements are ignored
+    pub fn add_element(&mut self, element: &[u8]) {
+        if !element.is_empty() {
+            self.elements.insert(element.to_vec());
+        }
+    }
+
+    /// Amount of distinct elements added so far
+    pub fn len(&self) -> usize {
+        self.elements.len()
+    }
+
+    /// Check whether no elements were added
+    pub fn is_empty(&self) -> bool {
+        self.elements.is_empty()
+    }
+
+    /// Write the filter to the wrapped writer
+    pub fn finish(&mut self) -> Result<usize, io::Error> {
+        write_filter(
+            &mut self.writer,
+            self.k0,
+            self.k1,
+            self.params,
+            &self.elements,
+        )
+    }
+}
+
+/// Iterator over the sorted hashed values of a filter
+pub struct GcsValues<R> {
+    reader: BitStreamReader<R>,
+    p: u8,
+    remaining: u64,
+    last: u64,
+}
+
+impl<R> GcsValues<R> {
+    /// Amount of values not yet read
+    pub fn remaining(&self) -> u64 {
+        self.remaining
+    }
+}
+
+impl<R: io::Read> Iterator for GcsValues<R> {
+    type Item = Result<u64, io::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        match golomb_rice_decode(&mut self.reader, self.p) {
+            Ok(delta) => {
+                self.last = self.last.wrapping_add(delta);
+                Some(Ok(self.last))
+            }
+            Err(e) => {
+                self.remaining = 0;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Golomb-Rice coded set reader
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GcsFilterReader {
+    k0: u64,
+    k1: u64,
+    params: GcsParams,
+}
+
+impl GcsFilterReader {
+    /// Create a new reader with siphash keys of the filter
+    pub fn new(k0: u64, k1: u64, params: GcsParams) -> GcsFilterReader {
+        GcsFilterReader { k0, k1, params }
+    }
+
+    /// Read the amount of elements from the head of the filter, malformed filters are empty
+    pub fn n_elements<R: io::Read>(reader: R) -> u64 {
+        VarInt::consensus_decode(reader).map(|n| n.0).unwrap_or(0)
+    }
+
+    /// Iterate over the sorted hashed values of the filter
+    pub fn values<R: io::Read>(&self, mut reader: R) -> GcsValues<R> {
+        let remaining = GcsFilterReader::n_elements(&mut reader);
+        GcsValues {
+            reader: BitStreamReader::new(reader),
+            p: self.params.p,
+            remaining,
+            last: 0,
+        }
+    }
+
+    /// Decode all values of the filter, returns amount of elements and the sorted values
+    pub fn decode(&self, content: &[u8]) -> Result<(u64, Vec<u64>), Error> {
+        let values = self.values(content);
+        let n_elements = values.remaining();
+        // every value takes at least one bit, don't trust the count for allocation
+        let capacity = cmp::min(n_elements, content.len() as u64 * 8);
+        let mut result = Vec::with_capacity(capacity as usize);
+        for value in values {
+            result.push(value?);
+        }
+        Ok((n_elements, result))
+    }
+
+    /// Range of hashed values of a filter with the given amount of elements, fails for a count
+    /// too large for any filter
+    pub fn range(&self, n_elements: u64) -> Result<u64, Error> {
+        n_elements.checked_mul(self.params.m).ok_or_else(|| {
+            Error::Io(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "filter element count is too large",
+            ))
+        })
+    }
+
+    /// Map an element to a value of a filter with the given amount of elements
+    pub fn map(&self, n_elements: u64, element: &[u8]) -> Result<u64, Error> {
+        Ok(self.map_to_range(self.range(n_elements)?, element))
+    }
+
+    fn map_to_range(&self, nm: u64, element: &[u8]) -> u64 {
+        map_to_range(hash_element(self.k0, self.k1, element), nm)
+    }
+
+    /// Check whether a single element is in the filter
+    pub fn contains<R: io::Read>(&self, reader: R, element: &[u8]) -> Result<bool, Error> {
+        let mut values = self.values(reader);
+        let value = self.map(values.remaining(), element)?;
+        for data in &mut values {
+            let data = data?;
+            if data >= value {
+                return Ok(data == value);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Match any query pattern, an empty query matches
+    pub fn match_any<R: io::Read>(
+        &self,
+        reader: R,
+        query: &mut dyn Iterator<Item = &[u8]>,
+    ) -> Result<bool, Error> {
+        let mut values = self.values(reader);
+        let nm = self.range(values.remaining())?;
+        let mut mapped: Vec<_> = query.map(|e| self.map_to_range(nm, e)).collect();
+        if mapped.is_empty() {
+            return Ok(true);
+        }
+        mapped.sort_unstable();
+        let mut data = match values.next() {
+            Some(data) => data?,
+            None => return Ok(false),
+        };
+        for p in mapped {
+            while data < p {
+                data = match values.next() {
+                    Some(data) => data?,
+                    None => return Ok(false),
+                };
+            }
+            if data == p {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Match all query pattern, an empty query matches
+    pub fn match_all<R: io::Read>(
+        &self,
+        reader: R,
+        query: &mut dyn Iterator<Item = &[u8]>,
+    ) -> Result<bool, Error> {
+        let mut values = self.values(reader);
+        let nm = self.range(values.remaining())?;
+        let mut mapped: Vec<_> = query.map(|e| self.map_to_range(nm, e)).collect();
+        if mapped.is_empty() {
+            return Ok(true);
+        }
+        mapped.sort_unstable();
+        mapped.dedup();
+        let mut data = match values.next() {
+            Some(data) => data?,
+            None => return Ok(false),
+        };
+        for p in mapped {
+            while data < p {
+                data = match values.next() {
+                    Some(data) => data?,
+                    None => return Ok(false),
+                };
+            }
+            if data != p {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Find which query elements are in the filter in one pass over the filter.
+    ///
+    /// Indices are returned in ascending order, each index at most once.
+    pub fn match_set<R: io::Read, T: AsRef<[u8]>>(
+        &self,
+        reader: R,
+        query: &[T],
+    ) -> Result<Vec<usize>, Error> {
+        let mut values = self.values(reader);
+        let nm = self.range(values.remaining())?;
+        let mut mapped: Vec<_> = query
+            .iter()
+            .enumerate()
+            .map(|(i, e)| (self.map_to_range(nm, e.as_ref()), i))
+            .collect();
+        mapped.sort_unstable();
+
+        let mut matched = vec![];
+        let mut data = match values.next() {
+            Some(data) => data?,
+            None => return Ok(matched),
+        };
+        'query: for (p, index) in mapped {
+            while data < p {
+                data = match values.next() {
+                    Some(data) => data?,
+                    None => break 'query,
+                };
+            }
+            if data == p {
+                matched.push(index);
+            }
+        }
+        matched.sort_unstable();
+        Ok(matched)
+    }
+}
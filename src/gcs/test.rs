@@ -0,0 +1,190 @@
+use crate::btc::ErgveinFilter;
+use crate::decoded::DecodedFilter;
+use crate::gcs::*;
+use crate::util::{block_filter_keys, GcsParams, ParamsError};
+use bitcoin::consensus::{deserialize, serialize};
+use bitcoin::hashes::hex::FromHex;
+use bitcoin::util::bip158::{GCSFilterReader, GCSFilterWriter};
+use bitcoin::BlockHash;
+
+fn elements() -> Vec<Vec<u8>> {
+    (0u32..500)
+        .map(|i| (i * 7919).to_le_bytes().repeat(1 + i as usize % 5))
+        .collect()
+}
+
+#[test]
+fn bit_streams() {
+    let mut out = Vec::new();
+    {
+        let mut writer = BitStreamWriter::new(&mut out);
+        writer.write(0b101, 3).unwrap();
+        writer.write(!0u64, 64).unwrap();
+        writer.write(0, 1).unwrap();
+        writer.write(0x1234, 13).unwrap();
+        writer.flush().unwrap();
+    }
+    assert_eq!(out.len(), 11);
+    let mut reader = BitStreamReader::new(out.as_slice());
+    assert_eq!(reader.read(3).unwrap(), 0b101);
+    assert_eq!(reader.read(64).unwrap(), !0u64);
+    assert_eq!(reader.read(1).unwrap(), 0);
+    assert_eq!(reader.read(13).unwrap(), 0x1234);
+    assert!(reader.read(65).is_err());
+    assert!(reader.read(8).is_err());
+}
+
+#[test]
+fn compatible_with_bitcoin() {
+    let params = [
+        GcsParams::BIP158,
+        GcsParams { p: 5, m: 32 },
+        GcsParams { p: 0, m: 1 },
+    ];
+    for params in params.iter().copied() {
+        let mut expected = Vec::new();
+        {
+            let mut writer = GCSFilterWriter::new(&mut expected, 1, 2, params.m, params.p);
+            for e in elements() {
+                writer.add_element(&e);
+            }
+            writer.finish().unwrap();
+        }
+        let mut content = Vec::new();
+        {
+            let mut writer = GcsFilterWriter::new(&mut content, 1, 2, params);
+            for e in elements() {
+                writer.add_element(&e);
+            }
+            writer.add_element(&[]);
+            assert_eq!(writer.len(), 500);
+            writer.finish().unwrap();
+        }
+        assert_eq!(expected, content);
+
+        let bitcoin_reader = GCSFilterReader::new(1, 2, params.m, params.p);
+        let reader = GcsFilterReader::new(1, 2, params);
+        let query = [b"missing".to_vec(), elements()[42].clone()];
+        for q in &[&query[..1], &query[1..], &query[..]] {
+            assert_eq!(
+                bitcoin_reader
+                    .match_any(&mut content.as_slice(), &mut q.iter().map(|e| e.as_slice()))
+                    .unwrap(),
+                reader
+                    .match_any(content.as_slice(), &mut q.iter().map(|e| e.as_slice()))
+                    .unwrap()
+            );
+            assert_eq!(
+                bitcoin_reader
+                    .match_all(&mut content.as_slice(), &mut q.iter().map(|e| e.as_slice()))
+                    .unwrap(),
+                reader
+                    .match_all(content.as_slice(), &mut q.iter().map(|e| e.as_slice()))
+                    .unwrap()
+            );
+        }
+    }
+}
+
+#[test]
+fn element_iteration() {
+    let params = GcsParams::BIP158;
+    let mut content = Vec::new();
+    {
+        let mut writer = GcsFilterWriter::new(&mut content, 3, 4, params);
+        for e in elements() {
+            writer.add_element(&e);
+        }
+        writer.finish().unwrap();
+    }
+    let reader = GcsFilterReader::new(3, 4, params);
+    assert_eq!(GcsFilterReader::n_elements(content.as_slice()), 500);
+    let mut expected: Vec<_> = elements()
+        .iter()
+        .map(|e| reader.map(500, e).unwrap())
+        .collect();
+    expected.sort_unstable();
+    let values: Vec<_> = reader
+        .values(content.as_slice())
+        .collect::<Result<_, _>>()
+        .unwrap();
+    assert_eq!(expected, values);
+    assert_eq!((500, values), reader.decode(&content).unwrap());
+
+    let mut rewritten = Vec::new();
+    write_values(&mut rewritten, &expected, params.p).unwrap();
+    assert_eq!(content, rewritten);
+
+    for e in elements() {
+        assert!(reader.contains(content.as_slice(), &e).unwrap());
+    }
+    assert!(!reader.contains(content.as_slice(), b"missing").unwrap());
+    assert!(reader.decode(&content[..content.len() / 2]).is_err());
+}
+
+#[test]
+fn bip158_testnet_genesis() {
+    // First filter of testnet, keyed by the genesis block hash
+    let content = Vec::<u8>::from_hex("019dfca8").unwrap();
+    let genesis =
+        BlockHash::from_hex("000000000933ea01ad0ee984209779baaec3ced90fa3f408719526f8d77f4943")
+            .unwrap();
+    let (k0, k1) = block_filter_keys(&genesis);
+    let reader = GcsFilterReader::new(k0, k1, GcsParams::BIP158);
+    let values = reader.decode(&content).unwrap();
+    assert_eq!(values.0, 1);
+    // the only element is the coinbase output script
+    let coinbase_script = Vec::<u8>::from_hex("4104678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac").unwrap();
+    assert!(reader
+        .contains(content.as_slice(), &coinbase_script)
+        .unwrap());
+    let mut rewritten = Vec::new();
+    write_values(&mut rewritten, &values.1, GcsParams::BIP158.p).unwrap();
+    assert_eq!(content, rewritten);
+}
+
+#[test]
+fn invalid_params() {
+    assert_eq!(GcsParams::new(19, 784931), Ok(GcsParams::BIP158));
+    assert_eq!(GcsParams::new(0, 1), Err(ParamsError::P(0)));
+    assert_eq!(GcsParams::new(33, 1), Err(ParamsError::P(33)));
+    assert_eq!(GcsParams::new(19, 0), Err(ParamsError::ZeroM));
+
+    let mut bytes = serialize(&GcsParams::BIP158);
+    assert_eq!(deserialize::<GcsParams>(&bytes).unwrap(), GcsParams::BIP158);
+    bytes[0] = 64;
+    assert!(deserialize::<GcsParams>(&bytes).is_err());
+    #[cfg(feature = "serde")]
+    assert!(serde_json::from_str::<GcsParams>(r#"{"p":64,"m":784931}"#).is_err());
+
+    // parameters built by hand are rejected by the coder instead of panicking
+    let mut reader = BitStreamReader::new([0xff; 16].as_ref());
+    assert!(golomb_rice_decode(&mut reader, 64).is_err());
+    let mut writer = BitStreamWriter::new(vec![]);
+    assert!(golomb_rice_encode(&mut writer, 1, 64).is_err());
+}
+
+#[test]
+fn untrusted_element_count() {
+    let mut content = vec![0xff; 9];
+    content.extend_from_slice(&[0; 8]);
+    let reader = GcsFilterReader::new(1, 2, GcsParams::BIP158);
+    let query = [b"a".to_vec(), b"b".to_vec()];
+    assert!(reader.range(u64::MAX).is_err());
+    assert!(reader.map(u64::MAX, b"a").is_err());
+    assert!(reader.contains(content.as_slice(), b"a").is_err());
+    assert!(reader
+        .match_any(content.as_slice(), &mut query.iter().map(|e| e.as_slice()))
+        .is_err());
+    assert!(reader
+        .match_all(content.as_slice(), &mut query.iter().map(|e| e.as_slice()))
+        .is_err());
+    assert!(reader.match_set(content.as_slice(), &query).is_err());
+
+    let filter = ErgveinFilter::new(&content);
+    let block_hash = BlockHash::default();
+    assert!(filter
+        .match_any(&block_hash, &mut query.iter().map(|e| e.as_slice()))
+        .is_err());
+    assert!(DecodedFilter::from_block_filter(&filter, &block_hash).is_err());
+}
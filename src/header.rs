@@ -0,0 +1,148 @@
+use crate::btc::ErgveinFilter;
+use bitcoin::{FilterHash, FilterHeader};
+use std::error;
+use std::fmt;
+
+#[cfg(test)]
+mod test;
+
+/// Errors of filter header chain verification
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The chain has no header at the given height
+    UnknownHeight(u32),
+    /// Header at the given height differs from the expected one
+    Mismatch {
+        height: u32,
+        expected: FilterHeader,
+        actual: FilterHeader,
+    },
+}
+
+impl error::Error for Error {}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::UnknownHeight(height) => write!(f, "no filter header at height {}", height),
+            Error::Mismatch {
+                height,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "filter header mismatch at height {}: expected {}, got {}",
+                height, expected, actual
+            ),
+        }
+    }
+}
+
+/// Chain of BIP157 filter headers, each one is `double_sha256(filter_hash || prev_header)`.
+///
+/// The chain either starts at genesis (with all zeros previous header) or at a trusted
+/// checkpoint and is extended block by block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilterHeaderChain {
+    /// Height of the first header in `headers`
+    start_height: u32,
+    /// Header that precedes the first one in `headers`
+    prev: FilterHeader,
+    headers: Vec<FilterHeader>,
+}
+
+impl Default for FilterHeaderChain {
+    fn default() -> Self {
+        FilterHeaderChain::new()
+    }
+}
+
+impl FilterHeaderChain {
+    /// Create an empty chain, the first pushed filter is the genesis one
+    pub fn new() -> FilterHeaderChain {
+        FilterHeaderChain {
+            start_height: 0,
+            prev: FilterHeader::default(),
+            headers: vec![],
+        }
+    }
+
+    /// Create a chain that continues from a trusted header at the given height, `None` if no
+    /// block can follow the height
+    pub fn from_checkpoint(height: u32, header: FilterHeader) -> Option<FilterHeaderChain> {
+        Some(FilterHeaderChain {
+            start_height: height.checked_add(1)?,
+            prev: header,
+            headers: vec![],
+        })
+    }
+
+    /// Height of the last header, `None` for an empty chain started from genesis
+    pub fn tip_height(&self) -> Option<u32> {
+        (self.start_height + self.headers.len() as u32).checked_sub(1)
+    }
+
+    /// The last header of the chain, all zeros for an empty chain started from genesis
+    pub fn tip(&self) -> FilterHeader {
+        self.headers.last().copied().unwrap_or(self.prev)
+    }
+
+    /// Get header at the given height
+    pub fn header(&self, height: u32) -> Option<FilterHeader> {
+        if height >= self.start_height {
+            self.headers
+                .get((height - self.start_height) as usize)
+                .copied()
+        } else if self.start_height > 0 && height == self.start_height - 1 {
+            Some(self.prev)
+        } else {
+            None
+        }
+    }
+
+    /// Extend the chain with the next block filter, returns the new tip
+    pub fn push(&mut self, filter: &ErgveinFilter) -> FilterHeader {
+        self.push_hash(filter.filter_hash())
+    }
+
+    /// Extend the chain with hash of the next block filter, returns the new tip
+    pub fn push_hash(&mut self, filter_hash: FilterHash) -> FilterHeader {
+        let header = filter_hash.filter_header(&self.tip());
+        self.headers.push(header);
+        header
+    }
+
+    /// Check that the header at the given height equals the expected one
+    pub fn verify_checkpoint(&self, height: u32, expected: &FilterHeader) -> Result<(), Error> {
+        let actual = self.header(height).ok_or(Error::UnknownHeight(height))?;
+        if actual == *expected {
+            Ok(())
+        } else {
+            Err(Error::Mismatch {
+                height,
+                expected: *expected,
+                actual,
+            })
+        }
+    }
+
+    /// Check all checkpoints, heights that are not yet in the chain are skipped
+    pub fn verify_checkpoints(&self, checkpoints: &[(u32, FilterHeader)]) -> Result<(), Error> {
+        for (height, expected) in checkpoints {
+            if self.header(*height).is_some() {
+                self.verify_checkpoint(*height, expected)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Drop all headers above the given height, e.g. when blocks are disconnected on reorg
+    pub fn rewind(&mut self, height: u32) -> Result<(), Error> {
+        if self.header(height).is_none() {
+            return Err(Error::UnknownHeight(height));
+        }
+        self.headers
+            .truncate((height + 1 - self.start_height) as usize);
+        Ok(())
+    }
+}
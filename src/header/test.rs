@@ -0,0 +1,72 @@
+use crate::btc::ErgveinFilter;
+use crate::header::{Error, FilterHeaderChain};
+use bitcoin::hashes::hex::FromHex;
+use bitcoin::FilterHeader;
+
+#[test]
+fn genesis_header() {
+    // Testnet genesis basic filter from BIP158 test vectors
+    let filter = ErgveinFilter::new(&Vec::from_hex("019dfca8").unwrap());
+    let expected =
+        FilterHeader::from_hex("21584579b7eb08997773e5aeff3a7f932700042d0ed2a6129012b7d7ae81b750")
+            .unwrap();
+    assert_eq!(filter.filter_header(&FilterHeader::default()), expected);
+
+    let mut chain = FilterHeaderChain::new();
+    assert_eq!(chain.tip_height(), None);
+    assert_eq!(chain.push(&filter), expected);
+    assert_eq!(chain.tip_height(), Some(0));
+    assert_eq!(chain.verify_checkpoint(0, &expected), Ok(()));
+}
+
+#[test]
+fn checkpoints_and_rewind() {
+    let filters: Vec<ErgveinFilter> = (0u8..10)
+        .map(|i| ErgveinFilter::new(&[1, i, i, i]))
+        .collect();
+    let mut chain = FilterHeaderChain::new();
+    for filter in &filters {
+        chain.push(filter);
+    }
+    assert_eq!(chain.tip_height(), Some(9));
+    let checkpoint = chain.header(4).unwrap();
+
+    let mut resumed = FilterHeaderChain::from_checkpoint(4, checkpoint).unwrap();
+    for filter in &filters[5..] {
+        resumed.push(filter);
+    }
+    assert_eq!(resumed.tip(), chain.tip());
+    // height 2 is below the checkpoint and is skipped
+    assert_eq!(
+        resumed.verify_checkpoints(&[(2, checkpoint), (9, chain.tip())]),
+        Ok(())
+    );
+    assert_eq!(
+        resumed.verify_checkpoints(&[(9, chain.tip()), (6, checkpoint)]),
+        Err(Error::Mismatch {
+            height: 6,
+            expected: checkpoint,
+            actual: chain.header(6).unwrap(),
+        })
+    );
+    assert_eq!(
+        resumed.verify_checkpoint(5, &checkpoint),
+        Err(Error::Mismatch {
+            height: 5,
+            expected: checkpoint,
+            actual: chain.header(5).unwrap(),
+        })
+    );
+
+    resumed.rewind(6).unwrap();
+    assert_eq!(resumed.tip_height(), Some(6));
+    assert_eq!(resumed.tip(), chain.header(6).unwrap());
+    resumed.rewind(4).unwrap();
+    assert_eq!(resumed.tip(), checkpoint);
+    assert_eq!(resumed.rewind(3), Err(Error::UnknownHeight(3)));
+
+    assert_eq!(
+        FilterHeaderChain::from_checkpoint(u32::MAX, checkpoint),
+        None
+    );
+}
@@ -0,0 +1,252 @@
+use crate::btc::ErgveinFilter;
+use crate::policy::{MissingUtxoPolicy, ScriptPolicy};
+use crate::store::{self, FilterStore, StoredFilter};
+use bitcoin::util::bip158;
+use bitcoin::{Block, BlockHash, FilterHeader, OutPoint, Script};
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+
+#[cfg(test)]
+mod test;
+
+/// Errors of the filter index
+#[derive(Debug)]
+pub enum Error {
+    /// The block doesn't extend the tip of the active chain
+    NotOnTip {
+        tip: Option<BlockHash>,
+        prev: BlockHash,
+    },
+    /// The disconnected block is not the tip of the active chain
+    NotTip(BlockHash),
+    /// The block has no parent to rewind to, i.e. it is the genesis one
+    FirstBlock(BlockHash),
+    /// Amount of spent scripts doesn't match amount of inputs of the block
+    Prevouts { expected: usize, actual: usize },
+    /// Failed to build the filter
+    Filter(bip158::Error),
+    /// Failed to access the filter store
+    Store(store::Error),
+}
+
+impl error::Error for Error {}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::NotOnTip {
+                tip: Some(tip),
+                prev,
+            } => write!(
+                f,
+                "block with parent {} doesn't extend the tip {}",
+                prev, tip
+            ),
+            Error::NotOnTip { tip: None, prev } => {
+                write!(f, "block with parent {} can't start the index", prev)
+            }
+            Error::NotTip(hash) => write!(f, "block {} is not the tip", hash),
+            Error::FirstBlock(hash) => {
+                write!(
+                    f,
+                    "block {} has no parent to rewind to and can't be disconnected",
+                    hash
+                )
+            }
+            Error::Prevouts { expected, actual } => {
+                write!(f, "expected {} spent scripts, got {}", expected, actual)
+            }
+            Error::Filter(e) => write!(f, "failed to build filter: {}", e),
+            Error::Store(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<bip158::Error> for Error {
+    fn from(e: bip158::Error) -> Self {
+        Error::Filter(e)
+    }
+}
+
+impl From<store::Error> for Error {
+    fn from(e: store::Error) -> Self {
+        Error::Store(e)
+    }
+}
+
+/// Filters of the active chain kept up to date with connected and disconnected blocks.
+///
+/// Filters of the active chain and their headers are kept in the store, filters of disconnected
+/// blocks are kept in memory while they are at most `stale_depth` blocks below the tip, so
+/// clients that are still on a stale branch can be served. A stale block connected again reuses
+/// its filter.
+///
+/// An empty store is started with the genesis block, or with the block following the base given
+/// to `with_base`.
+pub struct FilterIndex<'a, S> {
+    store: S,
+    policy: &'a dyn ScriptPolicy,
+    stale_depth: u32,
+    stale: HashMap<BlockHash, StoredFilter>,
+    start_height: u32,
+    start_prev: BlockHash,
+    start_header: FilterHeader,
+}
+
+impl<'a, S: FilterStore> FilterIndex<'a, S> {
+    /// Continue indexing from the tip of the store, an empty store starts with the genesis block
+    pub fn new(store: S, policy: &'a dyn ScriptPolicy, stale_depth: u32) -> Self {
+        FilterIndex::with_base(
+            store,
+            policy,
+            stale_depth,
+            0,
+            BlockHash::default(),
+            FilterHeader::default(),
+        )
+    }
+
+    /// Continue indexing from the tip of the store, an empty store starts with the child of
+    /// `prev` at `height`, chaining its filter header to `prev_header`
+    pub fn with_base(
+        store: S,
+        policy: &'a dyn ScriptPolicy,
+        stale_depth: u32,
+        height: u32,
+        prev: BlockHash,
+        prev_header: FilterHeader,
+    ) -> Self {
+        FilterIndex {
+            store,
+            policy,
+            stale_depth,
+            stale: HashMap::new(),
+            start_height: height,
+            start_prev: prev,
+            start_header: prev_header,
+        }
+    }
+
+    /// Underlying store with filters of the active chain
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// Give back the store
+    pub fn into_store(self) -> S {
+        self.store
+    }
+
+    /// Height, hash and filter header of the tip of the active chain
+    pub fn tip(&self) -> Option<(u32, BlockHash, FilterHeader)> {
+        self.store.tip()
+    }
+
+    /// Check whether the block is in the active chain
+    pub fn is_active(&self, block_hash: &BlockHash) -> bool {
+        self.store.height_of(block_hash).is_some()
+    }
+
+    /// Filter of a block of the active chain or of a kept stale block
+    pub fn filter(&self, block_hash: &BlockHash) -> Result<Option<StoredFilter>, Error> {
+        match self.store.get_by_hash(block_hash)? {
+            Some(filter) => Ok(Some(filter)),
+            None => Ok(self.stale.get(block_hash).cloned()),
+        }
+    }
+
+    /// Amount of kept filters of stale blocks
+    pub fn n_stale(&self) -> usize {
+        self.stale.len()
+    }
+
+    /// Connect the next block of the active chain, returns the header of its filter.
+    ///
+    /// `prevouts` are the scripts spent by the block in order of inputs, skipping the coinbase,
+    /// as in undo data of Bitcoin Core.
+    pub fn connect_block(
+        &mut self,
+        block: &Block,
+        prevouts: &[Script],
+    ) -> Result<FilterHeader, Error> {
+        let block_hash = block.block_hash();
+        let prev = block.header.prev_blockhash;
+        let (height, prev_header) = match self.store.tip() {
+            Some((height, tip, header)) if tip == prev => (height + 1, header),
+            Some((_, tip, _)) => {
+                return Err(Error::NotOnTip {
+                    tip: Some(tip),
+                    prev,
+                })
+            }
+            None if prev == self.start_prev => (self.start_height, self.start_header),
+            None => return Err(Error::NotOnTip { tip: None, prev }),
+        };
+        let filter = match self.stale.get(&block_hash) {
+            Some(stale) => stale.filter.clone(),
+            None => self.build_filter(block, prevouts)?,
+        };
+        let header = filter.filter_header(&prev_header);
+        self.store.put(height, block_hash, &filter, header)?;
+        self.stale.remove(&block_hash);
+        self.prune(height);
+        Ok(header)
+    }
+
+    /// Disconnect the tip of the active chain, its filter is kept as a stale one
+    pub fn disconnect_block(&mut self, block_hash: &BlockHash) -> Result<(), Error> {
+        match self.store.tip() {
+            Some((_, tip, _)) if tip == *block_hash => (),
+            _ => return Err(Error::NotTip(*block_hash)),
+        }
+        let stored = self
+            .store
+            .get_by_hash(block_hash)?
+            .ok_or(Error::NotTip(*block_hash))?;
+        // the first block of the index rewinds to its base, other blocks need a stored parent
+        let has_parent = if stored.height == self.start_height {
+            stored.height > 0
+        } else {
+            self.store.header(stored.height.wrapping_sub(1)).is_some()
+        };
+        if !has_parent {
+            return Err(Error::FirstBlock(*block_hash));
+        }
+        self.store.rewind(stored.height - 1)?;
+        if self.stale_depth > 0 {
+            self.stale.insert(*block_hash, stored);
+        }
+        Ok(())
+    }
+
+    fn build_filter(&self, block: &Block, prevouts: &[Script]) -> Result<ErgveinFilter, Error> {
+        let spent: Vec<OutPoint> = block
+            .txdata
+            .iter()
+            .filter(|tx| !tx.is_coin_base())
+            .flat_map(|tx| tx.input.iter().map(|i| i.previous_output))
+            .collect();
+        if spent.len() != prevouts.len() {
+            return Err(Error::Prevouts {
+                expected: spent.len(),
+                actual: prevouts.len(),
+            });
+        }
+        let scripts: HashMap<OutPoint, &Script> = spent.into_iter().zip(prevouts).collect();
+        let (filter, _) =
+            ErgveinFilter::new_script_filter(block, self.policy, MissingUtxoPolicy::Fail, |o| {
+                scripts
+                    .get(o)
+                    .map(|s| (*s).clone())
+                    .ok_or(bip158::Error::UtxoMissing(*o))
+            })?;
+        Ok(filter)
+    }
+
+    /// Drop stale filters deeper than `stale_depth` below the tip
+    fn prune(&mut self, tip_height: u32) {
+        let min_height = tip_height.saturating_sub(self.stale_depth);
+        self.stale.retain(|_, f| f.height > min_height);
+    }
+}
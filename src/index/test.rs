@@ -0,0 +1,172 @@
+use crate::btc::ErgveinFilter;
+use crate::header::FilterHeaderChain;
+use crate::index::{Error, FilterIndex};
+use crate::policy::{FilterVersion, MissingUtxoPolicy};
+use crate::store::{FilterStore, MemoryFilterStore};
+use crate::test::utils::*;
+use bitcoin::blockdata::script::Builder;
+use bitcoin::hashes::{sha256d, Hash};
+use bitcoin::{Block, BlockHash, BlockHeader, FilterHeader, OutPoint, Transaction, TxIn, TxOut};
+
+fn make_block(prev: BlockHash, tag: u8) -> Block {
+    let coinbase = Transaction {
+        version: 1,
+        lock_time: 0,
+        input: vec![TxIn {
+            previous_output: OutPoint::null(),
+            script_sig: Builder::new().push_slice(&[tag]).into_script(),
+            sequence: 0xffffffff,
+            witness: vec![],
+        }],
+        output: vec![TxOut {
+            value: 50,
+            script_pubkey: Builder::new()
+                .push_int(0)
+                .push_slice(&[tag; 20])
+                .into_script(),
+        }],
+    };
+    Block {
+        header: BlockHeader {
+            version: 1,
+            prev_blockhash: prev,
+            merkle_root: Default::default(),
+            time: tag as u32,
+            bits: 0,
+            nonce: 0,
+        },
+        txdata: vec![coinbase],
+    }
+}
+
+fn make_chain(prev: BlockHash, tags: &[u8]) -> Vec<Block> {
+    let mut prev = prev;
+    tags.iter()
+        .map(|tag| {
+            let block = make_block(prev, *tag);
+            prev = block.block_hash();
+            block
+        })
+        .collect()
+}
+
+#[test]
+fn connect_with_prevouts() {
+    let block = load_block("./test/block1");
+    let txmap = block1_inputs();
+    let prevouts = block_prevouts(&block, &txmap);
+    let (expected, _) = ErgveinFilter::new_script_filter(
+        &block,
+        &FilterVersion::V0,
+        MissingUtxoPolicy::Fail,
+        script_for_coin(&txmap),
+    )
+    .unwrap();
+
+    // the block is not a genesis one
+    let mut index = FilterIndex::new(MemoryFilterStore::new(), &FilterVersion::V0, 6);
+    assert!(matches!(
+        index.connect_block(&block, &prevouts),
+        Err(Error::NotOnTip { tip: None, .. })
+    ));
+
+    let base_header = FilterHeader::from_hash(sha256d::Hash::hash(b"base"));
+    let mut index = FilterIndex::with_base(
+        MemoryFilterStore::new(),
+        &FilterVersion::V0,
+        6,
+        100,
+        block.header.prev_blockhash,
+        base_header,
+    );
+    assert!(matches!(
+        index.connect_block(&block, &prevouts[1..]),
+        Err(Error::Prevouts { .. })
+    ));
+    let header = index.connect_block(&block, &prevouts).unwrap();
+    assert_eq!(header, expected.filter_header(&base_header));
+    let stored = index.filter(&block.block_hash()).unwrap().unwrap();
+    assert_eq!(stored.filter, expected);
+    assert_eq!(stored.height, 100);
+
+    let next = make_block(block.block_hash(), 1);
+    index.connect_block(&next, &[]).unwrap();
+    assert_eq!(index.tip().unwrap().0, 101);
+    assert!(matches!(
+        index.connect_block(&make_block(BlockHash::default(), 2), &[]),
+        Err(Error::NotOnTip { .. })
+    ));
+    assert!(matches!(
+        index.disconnect_block(&block.block_hash()),
+        Err(Error::NotTip(_))
+    ));
+    index.disconnect_block(&next.block_hash()).unwrap();
+
+    // the first block rewinds to the base and is connected again from its stale filter
+    index.disconnect_block(&block.block_hash()).unwrap();
+    assert_eq!(index.tip(), None);
+    assert_eq!(index.connect_block(&block, &[]).unwrap(), header);
+    assert_eq!(index.tip().unwrap().0, 100);
+
+    let genesis = make_block(BlockHash::default(), 0);
+    let mut index = FilterIndex::new(MemoryFilterStore::new(), &FilterVersion::V0, 6);
+    index.connect_block(&genesis, &[]).unwrap();
+    assert!(matches!(
+        index.disconnect_block(&genesis.block_hash()),
+        Err(Error::FirstBlock(_))
+    ));
+}
+
+#[test]
+fn reorgs() {
+    let main = make_chain(BlockHash::default(), &[0, 1, 2, 3, 4]);
+    let fork = make_chain(main[2].block_hash(), &[13, 14, 15]);
+    let mut index = FilterIndex::new(MemoryFilterStore::new(), &FilterVersion::V0, 2);
+    for block in &main {
+        index.connect_block(block, &[]).unwrap();
+    }
+    assert_eq!(index.tip().unwrap().1, main[4].block_hash());
+
+    index.disconnect_block(&main[4].block_hash()).unwrap();
+    index.disconnect_block(&main[3].block_hash()).unwrap();
+    assert_eq!(index.n_stale(), 2);
+    for block in &fork {
+        index.connect_block(block, &[]).unwrap();
+    }
+
+    // the active chain has headers of the fork
+    let mut chain = FilterHeaderChain::new();
+    for block in main[..3].iter().chain(fork.iter()) {
+        let filter = index.filter(&block.block_hash()).unwrap().unwrap().filter;
+        chain.push(&filter);
+        assert!(index.is_active(&block.block_hash()));
+    }
+    assert_eq!(index.tip(), Some((5, fork[2].block_hash(), chain.tip())));
+    for height in 0..=5 {
+        assert_eq!(index.store().header(height), chain.header(height));
+    }
+
+    // stale block at height 4 is kept, the one at height 3 is too deep
+    assert!(!index.is_active(&main[4].block_hash()));
+    let stale = index.filter(&main[4].block_hash()).unwrap().unwrap();
+    assert_eq!(stale.height, 4);
+    assert!(index.filter(&main[3].block_hash()).unwrap().is_none());
+    assert_eq!(index.n_stale(), 1);
+
+    // switch back
+    for block in fork.iter().rev() {
+        index.disconnect_block(&block.block_hash()).unwrap();
+    }
+    for block in &main[3..] {
+        index.connect_block(block, &[]).unwrap();
+    }
+    assert_eq!(index.tip().unwrap().1, main[4].block_hash());
+    assert_eq!(
+        index.filter(&main[4].block_hash()).unwrap().unwrap().filter,
+        stale.filter
+    );
+    // the whole fork is within the stale depth from the tip
+    assert_eq!(index.n_stale(), 3);
+    let store = index.into_store();
+    assert_eq!(store.len(), 5);
+}
@@ -1,5 +1,16 @@
+pub mod blkfile;
 pub mod btc;
+pub mod builder;
+pub mod checkpoint;
+pub mod decoded;
+pub mod gcs;
+pub mod header;
+pub mod index;
 pub mod mempool;
+pub mod message;
+pub mod policy;
+pub mod scanner;
+pub mod store;
 pub mod util;
 
 #[cfg(test)]
@@ -1,101 +1,409 @@
+use crate::btc::AddressMatch;
+use crate::builder::{FilterBuilder, OutpointFilterBuilder};
+use crate::gcs::{GcsFilterReader, GcsFilterWriter};
+use crate::policy::{FilterId, MissingUtxoPolicy, ScriptPolicy};
 use crate::util::*;
-use bitcoin::util::bip158::{Error, GCSFilterReader, GCSFilterWriter};
-use bitcoin::{OutPoint, Script, Transaction};
+use bitcoin::consensus::{encode, Decodable, Encodable};
+use bitcoin::hashes::{sha256d, Hash};
+use bitcoin::secp256k1::rand;
+use bitcoin::util::bip158::Error;
+use bitcoin::{Address, BlockHash, OutPoint, Script, Transaction, Txid, VarInt};
 use std::io;
-use std::io::Cursor;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+pub mod delta;
+pub mod index;
 
 #[cfg(test)]
 mod test;
 
-/// Golomb encoding parameter as in BIP-158, see also https://gist.github.com/sipa/576d5f09c3b86c3b1b75598d799fc845
-const P: u8 = 19;
-const M: u64 = 784931;
+/// SipHash keys of a mempool filter.
+///
+/// Keys are derived from 16 bytes the same way as block filter keys are taken from a block hash:
+/// the first 8 bytes are `k0` and the next 8 bytes are `k1`, both little endian.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct MempoolFilterKey {
+    k0: u64,
+    k1: u64,
+}
+
+impl MempoolFilterKey {
+    /// Create keys from 16 bytes
+    pub fn from_bytes(bytes: [u8; 16]) -> MempoolFilterKey {
+        let mut k0 = [0u8; 8];
+        let mut k1 = [0u8; 8];
+        k0.copy_from_slice(&bytes[0..8]);
+        k1.copy_from_slice(&bytes[8..16]);
+        MempoolFilterKey {
+            k0: u64::from_le_bytes(k0),
+            k1: u64::from_le_bytes(k1),
+        }
+    }
+
+    /// Derive keys of a mempool snapshot from the chain tip and a sequence number of the snapshot
+    /// on top of the tip: `double_sha256(tip || sequence)` with little endian sequence.
+    pub fn from_snapshot(tip: &BlockHash, sequence: u64) -> MempoolFilterKey {
+        let mut engine = sha256d::Hash::engine();
+        tip.consensus_encode(&mut engine)
+            .expect("engines don't error");
+        sequence
+            .consensus_encode(&mut engine)
+            .expect("engines don't error");
+        let hash = sha256d::Hash::from_engine(engine);
+        let mut bytes = [0u8; 16];
+        bytes.copy_from_slice(&hash[0..16]);
+        MempoolFilterKey::from_bytes(bytes)
+    }
+
+    /// Generate random keys
+    pub fn random() -> MempoolFilterKey {
+        MempoolFilterKey::from_bytes(rand::random())
+    }
+
+    /// Get the 16 bytes the keys are made of
+    pub fn to_bytes(&self) -> [u8; 16] {
+        let mut bytes = [0u8; 16];
+        bytes[0..8].copy_from_slice(&self.k0.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.k1.to_le_bytes());
+        bytes
+    }
+
+    /// First SipHash key
+    pub fn k0(&self) -> u64 {
+        self.k0
+    }
+
+    /// Second SipHash key
+    pub fn k1(&self) -> u64 {
+        self.k1
+    }
+}
+
+impl Encodable for MempoolFilterKey {
+    fn consensus_encode<W: io::Write>(&self, writer: W) -> Result<usize, io::Error> {
+        self.to_bytes().consensus_encode(writer)
+    }
+}
+
+impl Decodable for MempoolFilterKey {
+    fn consensus_decode<D: io::Read>(d: D) -> Result<Self, encode::Error> {
+        Ok(MempoolFilterKey::from_bytes(Decodable::consensus_decode(
+            d,
+        )?))
+    }
+}
 
 /// A BIP158 like filter that diverge only in which data is added to the filter.
 ///
 /// Ergvein wallet adds only segwit scripts and data carrier to save bandwith for mobile clients.
+/// Mempool snapshots are short lived, so their Golomb coding parameters can be tuned for size.
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct ErgveinMempoolFilter {
     /// Golomb encoded filter
+    #[cfg_attr(feature = "serde", serde(with = "crate::util::serde_content"))]
     pub content: Vec<u8>,
+    /// Golomb coding parameters the filter is encoded with
+    pub params: GcsParams,
 }
 
 impl ErgveinMempoolFilter {
-    pub fn new(content: &[u8]) -> ErgveinMempoolFilter {
+    pub fn new(content: &[u8], params: GcsParams) -> ErgveinMempoolFilter {
         ErgveinMempoolFilter {
             content: content.to_vec(),
+            params,
         }
     }
 
-    /// Compute a SCRIPT_FILTER that contains spent and output scripts
+    /// Compute a SCRIPT_FILTER that contains spent and output scripts.
+    ///
+    /// Returns outpoints of inputs that were left out of the filter, they are collected only with
+    /// `MissingUtxoPolicy::Collect`.
     pub fn new_script_filter<M>(
-        k0: u64,
-        k1: u64,
+        key: &MempoolFilterKey,
+        params: GcsParams,
+        policy: &dyn ScriptPolicy,
+        missing: MissingUtxoPolicy,
         txs: Vec<Transaction>,
         script_for_coin: M,
-    ) -> Result<ErgveinMempoolFilter, Error>
+    ) -> Result<(ErgveinMempoolFilter, Vec<OutPoint>), Error>
     where
         M: Fn(&OutPoint) -> Result<Script, Error>,
     {
-        let mut out = Cursor::new(Vec::new());
-        {
-            let mut writer = MempoolFilterWriter::new(&mut out, k0, k1);
-            add_output_scripts(&mut writer, &txs);
-            add_input_scripts(&mut writer, &txs, script_for_coin)?;
-            writer.finish()?;
+        let mut builder = FilterBuilder::for_mempool(key, params, policy, missing);
+        // Outputs go first to resolve chains of unconfirmed transactions in any order
+        for tx in &txs {
+            builder.add_outputs(tx);
+        }
+        for tx in &txs {
+            builder.add_inputs(tx, &script_for_coin)?;
+        }
+        let skipped = builder.skipped().to_vec();
+        let filter = ErgveinMempoolFilter {
+            content: builder.finish()?,
+            params,
+        };
+        Ok((filter, skipped))
+    }
+
+    /// Compute a SCRIPT_FILTER resolving all spent scripts of the transactions with one call.
+    ///
+    /// The resolver returns `None` for unknown outpoints, they are handled by `missing` and
+    /// reported back as in `new_script_filter`.
+    pub fn new_script_filter_batch<R>(
+        key: &MempoolFilterKey,
+        params: GcsParams,
+        policy: &dyn ScriptPolicy,
+        missing: MissingUtxoPolicy,
+        txs: &[Transaction],
+        resolver: R,
+    ) -> Result<(ErgveinMempoolFilter, Vec<OutPoint>), Error>
+    where
+        R: FnOnce(&[OutPoint]) -> Result<Vec<Option<Script>>, Error>,
+    {
+        let mut builder = FilterBuilder::for_mempool(key, params, policy, missing);
+        for tx in txs {
+            builder.defer_transaction(tx);
+        }
+        builder.resolve_pending(resolver)?;
+        let skipped = builder.skipped().to_vec();
+        let filter = ErgveinMempoolFilter {
+            content: builder.finish()?,
+            params,
+        };
+        Ok((filter, skipped))
+    }
+
+    /// Compute an outpoint filter with txids of the transactions and outpoints spent by them, see
+    /// `FilterId::OUTPOINTS`
+    pub fn new_outpoint_filter(
+        key: &MempoolFilterKey,
+        params: GcsParams,
+        txs: &[Transaction],
+    ) -> Result<ErgveinMempoolFilter, Error> {
+        let mut builder = OutpointFilterBuilder::for_mempool(key, params);
+        for tx in txs {
+            builder.add_transaction(tx);
         }
         Ok(ErgveinMempoolFilter {
-            content: out.into_inner(),
+            content: builder.finish()?,
+            params,
         })
     }
 
+    /// Amount of elements in the filter, read from the head of the content
+    pub fn n_elements(&self) -> u64 {
+        GcsFilterReader::n_elements(self.content.as_slice())
+    }
+
     /// Match any transaction output scripts
-    pub fn match_tx_outputs(&self, k0: u64, k1: u64, tx: &Transaction) -> Result<bool, Error> {
+    pub fn match_tx_outputs(
+        &self,
+        key: &MempoolFilterKey,
+        tx: &Transaction,
+    ) -> Result<bool, Error> {
         let mut scripts = tx.output.iter().map(|o| o.script_pubkey.as_bytes());
-        self.match_any(k0, k1, &mut scripts)
+        self.match_any(key, &mut scripts)
     }
 
     /// match any query pattern
     pub fn match_any(
         &self,
-        k0: u64,
-        k1: u64,
+        key: &MempoolFilterKey,
         query: &mut dyn Iterator<Item = &[u8]>,
     ) -> Result<bool, Error> {
-        let filter_reader = MempoolFilterReader::new(k0, k1);
-        filter_reader.match_any(&mut Cursor::new(self.content.as_slice()), query)
+        let filter_reader = MempoolFilterReader::new(key, self.params);
+        filter_reader.match_any(&mut self.content.as_slice(), query)
     }
 
     /// match all query pattern
     pub fn match_all(
         &self,
-        k0: u64,
-        k1: u64,
+        key: &MempoolFilterKey,
         query: &mut dyn Iterator<Item = &[u8]>,
     ) -> Result<bool, Error> {
-        let filter_reader = MempoolFilterReader::new(k0, k1);
-        filter_reader.match_all(&mut Cursor::new(self.content.as_slice()), query)
+        let filter_reader = MempoolFilterReader::new(key, self.params);
+        filter_reader.match_all(&mut self.content.as_slice(), query)
+    }
+
+    /// Find which query elements are in the filter, returns their indices in ascending order
+    pub fn match_set<T: AsRef<[u8]>>(
+        &self,
+        key: &MempoolFilterKey,
+        query: &[T],
+    ) -> Result<Vec<usize>, Error> {
+        GcsFilterReader::new(key.k0, key.k1, self.params).match_set(self.content.as_slice(), query)
+    }
+
+    /// Find which outpoints are spent according to an outpoint filter, returns their indices
+    pub fn match_outpoints(
+        &self,
+        key: &MempoolFilterKey,
+        outpoints: &[OutPoint],
+    ) -> Result<Vec<usize>, Error> {
+        let query: Vec<_> = outpoints.iter().map(outpoint_element).collect();
+        self.match_set(key, &query)
+    }
+
+    /// Find which transactions are created according to an outpoint filter, returns their indices
+    pub fn match_txids(&self, key: &MempoolFilterKey, txids: &[Txid]) -> Result<Vec<usize>, Error> {
+        self.match_set(key, txids)
+    }
+
+    /// Match script pubkeys of the addresses, reporting addresses the policy never indexes
+    pub fn match_addresses(
+        &self,
+        key: &MempoolFilterKey,
+        policy: &dyn ScriptPolicy,
+        addresses: &[Address],
+    ) -> Result<AddressMatch, Error> {
+        AddressMatch::new(policy, addresses, |scripts| self.match_set(key, scripts))
+    }
+}
+
+impl Encodable for ErgveinMempoolFilter {
+    fn consensus_encode<W: io::Write>(&self, mut writer: W) -> Result<usize, io::Error> {
+        let len = self.params.consensus_encode(&mut writer)?;
+        Ok(len + self.content.consensus_encode(&mut writer)?)
+    }
+}
+
+impl Decodable for ErgveinMempoolFilter {
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<Self, encode::Error> {
+        Ok(ErgveinMempoolFilter {
+            params: Decodable::consensus_decode(&mut d)?,
+            content: Decodable::consensus_decode(&mut d)?,
+        })
+    }
+}
+
+/// Self-describing serialized form of a mempool filter together with the keys to match it.
+///
+/// Golomb coding parameters are stored with the filter itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "RawMempoolFilterRecord"))]
+pub struct MempoolFilterRecord {
+    /// Type of the filter, see `ScriptPolicy::filter_type`
+    pub filter_type: u8,
+    /// Version of the filter rules, see `ScriptPolicy::version`
+    pub version: u8,
+    /// SipHash keys of the filter
+    pub key: MempoolFilterKey,
+    /// Amount of elements in the filter
+    pub n_elements: u64,
+    /// The filter itself
+    pub filter: ErgveinMempoolFilter,
+}
+
+/// Deserialized fields of a `MempoolFilterRecord` before the element count is checked
+#[cfg(feature = "serde")]
+#[derive(Deserialize)]
+struct RawMempoolFilterRecord {
+    filter_type: u8,
+    version: u8,
+    key: MempoolFilterKey,
+    n_elements: u64,
+    filter: ErgveinMempoolFilter,
+}
+
+#[cfg(feature = "serde")]
+impl std::convert::TryFrom<RawMempoolFilterRecord> for MempoolFilterRecord {
+    type Error = &'static str;
+
+    fn try_from(raw: RawMempoolFilterRecord) -> Result<Self, Self::Error> {
+        MempoolFilterRecord {
+            filter_type: raw.filter_type,
+            version: raw.version,
+            key: raw.key,
+            n_elements: raw.n_elements,
+            filter: raw.filter,
+        }
+        .checked()
+    }
+}
+
+impl MempoolFilterRecord {
+    /// Check that the stored element count matches the filter
+    fn checked(self) -> Result<Self, &'static str> {
+        if self.n_elements != self.filter.n_elements() {
+            return Err("filter element count doesn't match its content");
+        }
+        Ok(self)
+    }
+
+    /// Wrap a filter of the given kind built with the keys, see `ScriptPolicy::id`
+    pub fn new(id: FilterId, key: MempoolFilterKey, filter: ErgveinMempoolFilter) -> Self {
+        MempoolFilterRecord {
+            filter_type: id.filter_type,
+            version: id.version,
+            key,
+            n_elements: filter.n_elements(),
+            filter,
+        }
+    }
+
+    /// Kind of the filter in the record
+    pub fn id(&self) -> FilterId {
+        FilterId {
+            filter_type: self.filter_type,
+            version: self.version,
+        }
+    }
+
+    /// Check that the record holds a filter of the kind
+    pub fn is_valid_for(&self, id: FilterId) -> bool {
+        self.id() == id
+    }
+}
+
+impl Encodable for MempoolFilterRecord {
+    fn consensus_encode<W: io::Write>(&self, mut writer: W) -> Result<usize, io::Error> {
+        let mut len = self.filter_type.consensus_encode(&mut writer)?;
+        len += self.version.consensus_encode(&mut writer)?;
+        len += self.key.consensus_encode(&mut writer)?;
+        len += VarInt(self.n_elements).consensus_encode(&mut writer)?;
+        len += self.filter.consensus_encode(&mut writer)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for MempoolFilterRecord {
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<Self, encode::Error> {
+        MempoolFilterRecord {
+            filter_type: Decodable::consensus_decode(&mut d)?,
+            version: Decodable::consensus_decode(&mut d)?,
+            key: Decodable::consensus_decode(&mut d)?,
+            n_elements: VarInt::consensus_decode(&mut d)?.0,
+            filter: Decodable::consensus_decode(&mut d)?,
+        }
+        .checked()
+        .map_err(encode::Error::ParseFailed)
     }
 }
 
 /// Compiles and writes a block filter
 pub struct MempoolFilterWriter<'a> {
-    writer: GCSFilterWriter<'a>,
+    writer: GcsFilterWriter<&'a mut dyn io::Write>,
 }
 
 impl<'a> FilterWriter for MempoolFilterWriter<'a> {
     fn add_filter_element(&mut self, data: &[u8]) {
         self.writer.add_element(data);
     }
-    fn is_block_filter(&mut self) -> bool {
-        false
-    }
 }
 
 impl<'a> MempoolFilterWriter<'a> {
     /// Create a block filter writer
-    pub fn new(writer: &'a mut dyn io::Write, k0: u64, k1: u64) -> MempoolFilterWriter<'a> {
-        let writer = GCSFilterWriter::new(writer, k0, k1, M, P);
+    pub fn new(
+        writer: &'a mut dyn io::Write,
+        key: &MempoolFilterKey,
+        params: GcsParams,
+    ) -> MempoolFilterWriter<'a> {
+        let writer = GcsFilterWriter::new(writer, key.k0, key.k1, params);
         MempoolFilterWriter { writer }
     }
 
@@ -112,14 +420,14 @@ impl<'a> MempoolFilterWriter<'a> {
 
 /// Reads and interpret a block filter
 pub struct MempoolFilterReader {
-    reader: GCSFilterReader,
+    reader: GcsFilterReader,
 }
 
 impl MempoolFilterReader {
     /// Create a block filter reader
-    pub fn new(k0: u64, k1: u64) -> MempoolFilterReader {
+    pub fn new(key: &MempoolFilterKey, params: GcsParams) -> MempoolFilterReader {
         MempoolFilterReader {
-            reader: GCSFilterReader::new(k0, k1, M, P),
+            reader: GcsFilterReader::new(key.k0, key.k1, params),
         }
     }
 
@@ -0,0 +1,131 @@
+use crate::mempool::{ErgveinMempoolFilter, MempoolFilterKey};
+use bitcoin::consensus::{encode, Decodable, Encodable};
+use bitcoin::util::bip158::Error;
+use std::error;
+use std::fmt;
+use std::io;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// Filter with elements added to the mempool between two snapshots, see `MempoolIndex::delta`
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct MempoolDelta {
+    /// Sequence number of the snapshot the delta is based on
+    pub from: u64,
+    /// Sequence number of the snapshot the delta leads to
+    pub to: u64,
+    /// SipHash keys of the filter
+    pub key: MempoolFilterKey,
+    /// Filter with elements added between the snapshots
+    pub filter: ErgveinMempoolFilter,
+}
+
+impl Encodable for MempoolDelta {
+    fn consensus_encode<W: io::Write>(&self, mut writer: W) -> Result<usize, io::Error> {
+        let mut len = self.from.consensus_encode(&mut writer)?;
+        len += self.to.consensus_encode(&mut writer)?;
+        len += self.key.consensus_encode(&mut writer)?;
+        len += self.filter.consensus_encode(&mut writer)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for MempoolDelta {
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<Self, encode::Error> {
+        Ok(MempoolDelta {
+            from: Decodable::consensus_decode(&mut d)?,
+            to: Decodable::consensus_decode(&mut d)?,
+            key: Decodable::consensus_decode(&mut d)?,
+            filter: Decodable::consensus_decode(&mut d)?,
+        })
+    }
+}
+
+/// Delta doesn't continue the snapshot it is applied to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SequenceGap {
+    /// Sequence number of the snapshot
+    pub expected: u64,
+    /// Sequence number the delta is based on
+    pub actual: u64,
+}
+
+impl error::Error for SequenceGap {}
+
+impl fmt::Display for SequenceGap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "delta from sequence {} can't be applied to snapshot {}",
+            self.actual, self.expected
+        )
+    }
+}
+
+/// Client side view of the mempool: a base filter and deltas received after it.
+///
+/// Deltas only add elements, so transactions that left the mempool keep matching until the base
+/// filter is downloaded again.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MempoolSnapshot {
+    sequence: u64,
+    filters: Vec<(MempoolFilterKey, ErgveinMempoolFilter)>,
+}
+
+impl MempoolSnapshot {
+    /// Start from the full filter of the snapshot with the given sequence number
+    pub fn new(sequence: u64, key: MempoolFilterKey, base: ErgveinMempoolFilter) -> Self {
+        MempoolSnapshot {
+            sequence,
+            filters: vec![(key, base)],
+        }
+    }
+
+    /// Sequence number of the latest applied snapshot
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// Amount of deltas applied to the base filter
+    pub fn n_deltas(&self) -> usize {
+        self.filters.len() - 1
+    }
+
+    /// Apply delta that is based on the current snapshot
+    pub fn apply(&mut self, delta: MempoolDelta) -> Result<(), SequenceGap> {
+        if delta.from != self.sequence {
+            return Err(SequenceGap {
+                expected: self.sequence,
+                actual: delta.from,
+            });
+        }
+        self.sequence = delta.to;
+        if delta.filter.n_elements() > 0 {
+            self.filters.push((delta.key, delta.filter));
+        }
+        Ok(())
+    }
+
+    /// match any query pattern in the base filter or any delta
+    pub fn match_any(&self, query: &mut dyn Iterator<Item = &[u8]>) -> Result<bool, Error> {
+        let query: Vec<&[u8]> = query.collect();
+        for (key, filter) in &self.filters {
+            if filter.match_any(key, &mut query.iter().copied())? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// match all query pattern, each of them can be in the base filter or any delta
+    pub fn match_all(&self, query: &mut dyn Iterator<Item = &[u8]>) -> Result<bool, Error> {
+        for element in query {
+            if !self.match_any(&mut std::iter::once(element))? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
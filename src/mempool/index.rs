@@ -0,0 +1,281 @@
+use crate::gcs;
+use crate::mempool::delta::MempoolDelta;
+use crate::mempool::{ErgveinMempoolFilter, MempoolFilterKey};
+use crate::policy::{MissingUtxoPolicy, ScriptPolicy};
+use crate::util::*;
+use bitcoin::util::bip158::Error;
+use bitcoin::{OutPoint, Script, Transaction, Txid};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+#[cfg(test)]
+mod test;
+
+/// Indexed transaction of the mempool
+struct TxEntry {
+    /// Distinct filter elements contributed by the transaction
+    elements: Vec<Vec<u8>>,
+    /// Output scripts to resolve spends of unconfirmed transactions
+    outputs: Vec<Script>,
+}
+
+/// Element of the mempool filter
+struct ElementEntry {
+    /// Amount of indexed transactions with the element
+    count: usize,
+    /// Sequence number of the change that added the element
+    added: u64,
+    /// SipHash of the element with the current key
+    hash: u64,
+}
+
+/// Collects filter elements of a single transaction
+struct TxElements {
+    elements: HashSet<Vec<u8>>,
+    missing: MissingUtxoPolicy,
+    skipped: Vec<OutPoint>,
+}
+
+impl FilterWriter for TxElements {
+    fn add_filter_element(&mut self, data: &[u8]) {
+        if !data.is_empty() && !self.elements.contains(data) {
+            self.elements.insert(data.to_vec());
+        }
+    }
+    fn on_missing_utxo(&mut self, outpoint: &OutPoint, error: Error) -> Result<(), Error> {
+        self.missing.handle(outpoint, error, &mut self.skipped)
+    }
+}
+
+/// Mempool filter maintained from a stream of accepted, evicted and confirmed transactions.
+///
+/// Elements of each transaction are resolved once when it is accepted, scripts shared by several
+/// transactions are reference counted. Their SipHash values are kept sorted, so encoding the filter
+/// takes time linear in the amount of elements without hashing or sorting them again. The filter
+/// is encoded only when it is requested after the element set has changed, otherwise the last one
+/// is returned. Changing the key hashes all elements again.
+///
+/// Each change of the element set increments the sequence number, which identifies snapshots
+/// of the mempool for `delta` filters.
+pub struct MempoolIndex<'a> {
+    key: MempoolFilterKey,
+    params: GcsParams,
+    policy: &'a dyn ScriptPolicy,
+    missing: MissingUtxoPolicy,
+    txs: HashMap<Txid, TxEntry>,
+    counts: HashMap<Vec<u8>, ElementEntry>,
+    /// Amount of elements by their SipHash
+    hashes: BTreeMap<u64, usize>,
+    sequence: u64,
+    cache: Option<ErgveinMempoolFilter>,
+}
+
+impl<'a> MempoolIndex<'a> {
+    /// Create an empty index
+    pub fn new(
+        key: MempoolFilterKey,
+        params: GcsParams,
+        policy: &'a dyn ScriptPolicy,
+        missing: MissingUtxoPolicy,
+    ) -> MempoolIndex<'a> {
+        MempoolIndex {
+            key,
+            params,
+            policy,
+            missing,
+            txs: HashMap::new(),
+            counts: HashMap::new(),
+            hashes: BTreeMap::new(),
+            sequence: 0,
+            cache: None,
+        }
+    }
+
+    /// Keys of the emitted filters
+    pub fn key(&self) -> &MempoolFilterKey {
+        &self.key
+    }
+
+    /// Change keys of the emitted filters, e.g. for a new snapshot
+    pub fn set_key(&mut self, key: MempoolFilterKey) {
+        if key != self.key {
+            self.key = key;
+            self.cache = None;
+            self.hashes.clear();
+            for (element, entry) in self.counts.iter_mut() {
+                entry.hash = gcs::hash_element(key.k0(), key.k1(), element);
+                *self.hashes.entry(entry.hash).or_insert(0) += 1;
+            }
+        }
+    }
+
+    /// Add a transaction accepted to the mempool.
+    ///
+    /// Spends of other indexed transactions are resolved from the index, the rest are looked up
+    /// with `script_for_coin`. Returns outpoints of inputs left out of the filter with
+    /// `MissingUtxoPolicy::Collect`.
+    pub fn accept<F>(
+        &mut self,
+        tx: &Transaction,
+        script_for_coin: F,
+    ) -> Result<Vec<OutPoint>, Error>
+    where
+        F: Fn(&OutPoint) -> Result<Script, Error>,
+    {
+        let txid = tx.txid();
+        if self.txs.contains_key(&txid) {
+            return Ok(vec![]);
+        }
+        let mut collector = TxElements {
+            elements: HashSet::new(),
+            missing: self.missing,
+            skipped: vec![],
+        };
+        add_tx_output_scripts(&mut collector, self.policy, tx);
+        let txs = &self.txs;
+        add_tx_input_scripts(&mut collector, self.policy, tx, |o| {
+            match txs
+                .get(&o.txid)
+                .and_then(|e| e.outputs.get(o.vout as usize))
+            {
+                Some(script) => Ok(script.clone()),
+                None => script_for_coin(o),
+            }
+        })?;
+
+        let sequence = self.sequence + 1;
+        let mut changed = false;
+        for element in &collector.elements {
+            let key = &self.key;
+            let entry = self
+                .counts
+                .entry(element.clone())
+                .or_insert_with(|| ElementEntry {
+                    count: 0,
+                    added: sequence,
+                    hash: gcs::hash_element(key.k0(), key.k1(), element),
+                });
+            entry.count += 1;
+            if entry.count == 1 {
+                *self.hashes.entry(entry.hash).or_insert(0) += 1;
+                changed = true;
+            }
+        }
+        if changed {
+            self.changed();
+        }
+        let entry = TxEntry {
+            elements: collector.elements.into_iter().collect(),
+            outputs: tx.output.iter().map(|o| o.script_pubkey.clone()).collect(),
+        };
+        self.txs.insert(txid, entry);
+        Ok(collector.skipped)
+    }
+
+    /// Remove a transaction evicted from the mempool, returns `false` if it is not indexed
+    pub fn evict(&mut self, txid: &Txid) -> bool {
+        let entry = match self.txs.remove(txid) {
+            Some(entry) => entry,
+            None => return false,
+        };
+        let mut changed = false;
+        for element in entry.elements {
+            if let Some(entry) = self.counts.get_mut(&element) {
+                entry.count -= 1;
+                if entry.count == 0 {
+                    let hash = entry.hash;
+                    self.counts.remove(&element);
+                    if let Some(n) = self.hashes.get_mut(&hash) {
+                        *n -= 1;
+                        if *n == 0 {
+                            self.hashes.remove(&hash);
+                        }
+                    }
+                    changed = true;
+                }
+            }
+        }
+        if changed {
+            self.changed();
+        }
+        true
+    }
+
+    fn changed(&mut self) {
+        self.sequence += 1;
+        self.cache = None;
+    }
+
+    /// Remove transactions confirmed in a block, returns amount of removed ones
+    pub fn confirm(&mut self, txs: &[Transaction]) -> usize {
+        txs.iter().filter(|tx| self.evict(&tx.txid())).count()
+    }
+
+    /// Check whether the transaction is indexed
+    pub fn contains(&self, txid: &Txid) -> bool {
+        self.txs.contains_key(txid)
+    }
+
+    /// Amount of indexed transactions
+    pub fn len(&self) -> usize {
+        self.txs.len()
+    }
+
+    /// Check whether no transactions are indexed
+    pub fn is_empty(&self) -> bool {
+        self.txs.is_empty()
+    }
+
+    /// Amount of distinct elements in the filter
+    pub fn n_elements(&self) -> usize {
+        self.counts.len()
+    }
+
+    /// Sequence number of the current mempool state
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// Get filter of the current mempool state
+    pub fn filter(&mut self) -> Result<ErgveinMempoolFilter, Error> {
+        if let Some(filter) = &self.cache {
+            return Ok(filter.clone());
+        }
+        let hashes: Vec<u64> = self
+            .hashes
+            .iter()
+            .flat_map(|(hash, n)| std::iter::repeat(*hash).take(*n))
+            .collect();
+        let filter = self.encode(&hashes)?;
+        self.cache = Some(filter.clone());
+        Ok(filter)
+    }
+
+    /// Get filter with elements added after the snapshot with the given sequence number that are
+    /// still in the mempool. Removed elements are not tracked by deltas.
+    ///
+    /// All elements are scanned to find the added ones, only those are sorted.
+    pub fn delta(&self, since: u64) -> Result<MempoolDelta, Error> {
+        let mut added: Vec<u64> = self
+            .counts
+            .values()
+            .filter(|entry| entry.added > since)
+            .map(|entry| entry.hash)
+            .collect();
+        added.sort_unstable();
+        Ok(MempoolDelta {
+            from: since,
+            to: self.sequence,
+            key: self.key,
+            filter: self.encode(&added)?,
+        })
+    }
+
+    fn encode(&self, hashes: &[u64]) -> Result<ErgveinMempoolFilter, Error> {
+        let mut content = vec![];
+        gcs::write_hashes(&mut content, self.params, hashes)?;
+        Ok(ErgveinMempoolFilter {
+            content,
+            params: self.params,
+        })
+    }
+}
@@ -0,0 +1,112 @@
+use crate::mempool::delta::{MempoolSnapshot, SequenceGap};
+use crate::mempool::index::MempoolIndex;
+use crate::mempool::{ErgveinMempoolFilter, MempoolFilterKey};
+use crate::policy::{FilterVersion, MissingUtxoPolicy, ScriptPolicy};
+use crate::test::utils::*;
+use crate::util::GcsParams;
+
+#[test]
+fn accept_and_evict() {
+    let key = MempoolFilterKey::from_bytes(*b"qwertyuiopasdfgh");
+    let block = load_block("./test/block1");
+    let txmap = block1_inputs();
+    let script_for_coin = script_for_coin(&txmap);
+    let snapshot = |key: &MempoolFilterKey, txs: &[_]| {
+        ErgveinMempoolFilter::new_script_filter(
+            key,
+            GcsParams::BIP158,
+            &FilterVersion::V0,
+            MissingUtxoPolicy::Fail,
+            txs.to_vec(),
+            script_for_coin,
+        )
+        .unwrap()
+        .0
+    };
+    let txs = &block.txdata[1..];
+    let (first, second) = txs.split_at(txs.len() / 2);
+
+    let mut index = MempoolIndex::new(
+        key,
+        GcsParams::BIP158,
+        &FilterVersion::V0,
+        MissingUtxoPolicy::Fail,
+    );
+    for tx in txs {
+        index.accept(tx, script_for_coin).unwrap();
+    }
+    // Accepting a transaction twice changes nothing
+    index.accept(&txs[0], script_for_coin).unwrap();
+    assert_eq!(index.len(), txs.len());
+    assert_eq!(index.filter().unwrap(), snapshot(&key, txs));
+
+    assert_eq!(index.confirm(first), first.len());
+    assert!(!index.evict(&first[0].txid()));
+    assert_eq!(index.filter().unwrap(), snapshot(&key, second));
+
+    // elements are hashed again with a new key
+    let other = MempoolFilterKey::from_bytes(*b"asdfghjklzxcvbnm");
+    index.set_key(other);
+    assert_eq!(index.filter().unwrap(), snapshot(&other, second));
+
+    for tx in second {
+        assert!(index.evict(&tx.txid()));
+    }
+    assert!(index.is_empty());
+    assert_eq!(index.n_elements(), 0);
+}
+
+#[test]
+fn snapshot_deltas() {
+    let key = MempoolFilterKey::from_bytes(*b"qwertyuiopasdfgh");
+    let block = load_block("./test/block1");
+    let txmap = block1_inputs();
+    let script_for_coin = script_for_coin(&txmap);
+    let txs = &block.txdata[1..];
+    let (first, second) = txs.split_at(txs.len() / 2);
+
+    let mut index = MempoolIndex::new(
+        key,
+        GcsParams::BIP158,
+        &FilterVersion::V0,
+        MissingUtxoPolicy::Fail,
+    );
+    for tx in first {
+        index.accept(tx, script_for_coin).unwrap();
+    }
+    let base_sequence = index.sequence();
+    let mut client = MempoolSnapshot::new(base_sequence, key, index.filter().unwrap());
+
+    index.set_key(MempoolFilterKey::from_snapshot(&block.block_hash(), 1));
+    for tx in second {
+        index.accept(tx, script_for_coin).unwrap();
+    }
+    let delta = index.delta(base_sequence).unwrap();
+    assert!(delta.filter.content.len() < index.filter().unwrap().content.len());
+    assert_eq!(
+        index.delta(index.sequence()).unwrap().filter.n_elements(),
+        0
+    );
+
+    client.apply(delta.clone()).unwrap();
+    assert_eq!(client.sequence(), index.sequence());
+    assert_eq!(
+        client.apply(delta),
+        Err(SequenceGap {
+            expected: index.sequence(),
+            actual: base_sequence
+        })
+    );
+    for tx in txs {
+        let scripts: Vec<_> = tx
+            .output
+            .iter()
+            .map(|o| &o.script_pubkey)
+            .filter(|s| FilterVersion::V0.is_script_indexable(s))
+            .map(|s| s.as_bytes())
+            .collect();
+        if !scripts.is_empty() {
+            assert!(client.match_all(&mut scripts.into_iter()).unwrap());
+        }
+    }
+}
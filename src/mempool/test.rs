@@ -1,25 +1,36 @@
-use crate::mempool::ErgveinMempoolFilter;
+use crate::mempool::{ErgveinMempoolFilter, MempoolFilterKey, MempoolFilterRecord};
+use crate::policy::{FilterVersion, MissingUtxoPolicy, ScriptPolicy};
 use crate::test::utils::*;
 use crate::util::is_script_indexable;
+use crate::util::GcsParams;
+use bitcoin::consensus::{deserialize, serialize};
 use bitcoin::util::bip158::Error;
+use bitcoin::{Address, Network, OutPoint, Transaction, TxIn, TxOut};
 
 #[test]
 fn mempool_test() {
-    let k0 = u64::from_le_bytes(*b"qwertyui");
-    let k1 = u64::from_le_bytes(*b"opasdfgh");
+    let key = MempoolFilterKey::from_bytes(*b"qwertyuiopasdfgh");
     let block = load_block("./test/block1");
     let txmap = make_inputs_map(load_txs("./test/block1-txs"));
     let mut txs = block.txdata;
     txs.remove(0); // remove coinbase
     let txs2 = txs.clone();
-    let filter = ErgveinMempoolFilter::new_script_filter(k0, k1, txs, |o| {
-        if let Some(s) = txmap.get(o) {
-            Ok(s.clone())
-        } else {
-            Err(Error::UtxoMissing(o.clone()))
-        }
-    })
-    .unwrap();
+    let filter = ErgveinMempoolFilter::new_script_filter(
+        &key,
+        GcsParams::BIP158,
+        &FilterVersion::V0,
+        MissingUtxoPolicy::Fail,
+        txs,
+        |o| {
+            if let Some(s) = txmap.get(o) {
+                Ok(s.clone())
+            } else {
+                Err(Error::UtxoMissing(*o))
+            }
+        },
+    )
+    .unwrap()
+    .0;
 
     for (i, tx) in txs2.iter().enumerate() {
         let is_indexable = tx
@@ -28,10 +39,225 @@ fn mempool_test() {
             .any(|o| is_script_indexable(&o.script_pubkey));
         if is_indexable {
             assert!(
-                filter.match_tx_outputs(k0, k1, &tx).unwrap(),
+                filter.match_tx_outputs(&key, tx).unwrap(),
                 "Tx #{} failed",
                 i
             );
         }
     }
 }
+
+/// Mempool filter of non-coinbase transactions of `./test/block1`
+fn block1_mempool_filter(key: &MempoolFilterKey) -> (ErgveinMempoolFilter, Vec<Transaction>) {
+    let txmap = block1_inputs();
+    let txs = load_block("./test/block1").txdata.split_off(1);
+    let filter = ErgveinMempoolFilter::new_script_filter(
+        key,
+        GcsParams::BIP158,
+        &FilterVersion::V0,
+        MissingUtxoPolicy::Fail,
+        txs.clone(),
+        script_for_coin(&txmap),
+    )
+    .unwrap()
+    .0;
+    (filter, txs)
+}
+
+#[test]
+fn mempool_match_set() {
+    let key = MempoolFilterKey::from_bytes(*b"qwertyuiopasdfgh");
+    let (filter, txs) = block1_mempool_filter(&key);
+    for (i, tx) in txs.iter().enumerate() {
+        let scripts: Vec<_> = tx
+            .output
+            .iter()
+            .map(|o| o.script_pubkey.as_bytes())
+            .collect();
+        let matched = filter.match_set(&key, &scripts).unwrap();
+        for (j, s) in tx.output.iter().enumerate() {
+            if is_script_indexable(&s.script_pubkey) {
+                assert!(matched.contains(&j), "Tx #{} output #{} failed", i, j);
+            }
+        }
+    }
+}
+
+#[test]
+fn mempool_match_addresses() {
+    let key = MempoolFilterKey::from_bytes(*b"qwertyuiopasdfgh");
+    let (filter, txs) = block1_mempool_filter(&key);
+    let addresses: Vec<_> = txs
+        .iter()
+        .flat_map(|tx| tx.output.iter())
+        .filter_map(|o| Address::from_script(&o.script_pubkey, Network::Bitcoin))
+        .collect();
+    let result = filter
+        .match_addresses(&key, &FilterVersion::V0, &addresses)
+        .unwrap();
+    assert!(!result.matched.is_empty());
+    assert!(!result.unindexable.is_empty());
+    for (i, address) in addresses.iter().enumerate() {
+        if is_script_indexable(&address.script_pubkey()) {
+            assert!(result.matched.contains(&i), "Address {} failed", address);
+        } else {
+            assert!(
+                result.unindexable.contains(&i),
+                "Address {} failed",
+                address
+            );
+        }
+    }
+}
+
+#[test]
+fn unconfirmed_chain() {
+    let key = MempoolFilterKey::from_bytes(*b"qwertyuiopasdfgh");
+    let block = load_block("./test/block1");
+    let txmap = block1_inputs();
+    let parent = block
+        .txdata
+        .iter()
+        .find(|tx| tx.output.iter().any(|o| o.script_pubkey.is_v0_p2wpkh()))
+        .unwrap()
+        .clone();
+    let vout = parent
+        .output
+        .iter()
+        .position(|o| o.script_pubkey.is_v0_p2wpkh())
+        .unwrap();
+    let child = Transaction {
+        version: 2,
+        lock_time: 0,
+        input: vec![TxIn {
+            previous_output: OutPoint::new(parent.txid(), vout as u32),
+            script_sig: Default::default(),
+            sequence: 0xffffffff,
+            witness: vec![vec![1]],
+        }],
+        output: vec![TxOut {
+            value: 1000,
+            script_pubkey: parent.output[vout].script_pubkey.clone(),
+        }],
+    };
+    // The child goes first and its parent output is unknown to the lookup
+    let filter = ErgveinMempoolFilter::new_script_filter(
+        &key,
+        GcsParams::BIP158,
+        &FilterVersion::V0,
+        MissingUtxoPolicy::Fail,
+        vec![child.clone(), parent],
+        script_for_coin(&txmap),
+    )
+    .unwrap()
+    .0;
+    assert!(filter.match_tx_outputs(&key, &child).unwrap());
+}
+
+#[test]
+fn mempool_record() {
+    let key = MempoolFilterKey::from_bytes(*b"qwertyuiopasdfgh");
+    let block = load_block("./test/block1");
+    let txmap = block1_inputs();
+    let (filter, _) = ErgveinMempoolFilter::new_script_filter(
+        &key,
+        GcsParams::BIP158,
+        &FilterVersion::V0,
+        MissingUtxoPolicy::Fail,
+        block.txdata[1..].to_vec(),
+        script_for_coin(&txmap),
+    )
+    .unwrap();
+    let record = MempoolFilterRecord::new(FilterVersion::V0.id(), key, filter);
+    assert!(record.is_valid_for(FilterVersion::V0.id()));
+    assert_eq!(record.n_elements, record.filter.n_elements());
+    assert_eq!(
+        deserialize::<MempoolFilterRecord>(&serialize(&record)).unwrap(),
+        record
+    );
+
+    #[cfg(feature = "serde")]
+    {
+        let json = serde_json::to_string(&record).unwrap();
+        assert_eq!(
+            serde_json::from_str::<MempoolFilterRecord>(&json).unwrap(),
+            record
+        );
+        let bytes = bincode::serialize(&record).unwrap();
+        assert_eq!(
+            bincode::deserialize::<MempoolFilterRecord>(&bytes).unwrap(),
+            record
+        );
+        let mut json = serde_json::to_value(&record).unwrap();
+        json["n_elements"] = (record.n_elements + 1).into();
+        assert!(serde_json::from_value::<MempoolFilterRecord>(json).is_err());
+    }
+}
+
+#[test]
+fn custom_params() {
+    let key = MempoolFilterKey::from_bytes(*b"qwertyuiopasdfgh");
+    let block = load_block("./test/block1");
+    let txmap = block1_inputs();
+    let build = |params| {
+        ErgveinMempoolFilter::new_script_filter(
+            &key,
+            params,
+            &FilterVersion::V0,
+            MissingUtxoPolicy::Fail,
+            block.txdata[1..].to_vec(),
+            script_for_coin(&txmap),
+        )
+        .unwrap()
+        .0
+    };
+    let small = GcsParams { p: 10, m: 1024 };
+    let filter = build(small);
+    let default_filter = build(GcsParams::default());
+    assert_eq!(filter.params, small);
+    assert!(filter.content.len() < default_filter.content.len());
+    for tx in &block.txdata[1..] {
+        if default_filter.match_tx_outputs(&key, tx).unwrap() {
+            assert!(filter.match_tx_outputs(&key, tx).unwrap());
+        }
+    }
+    let decoded: ErgveinMempoolFilter = deserialize(&serialize(&filter)).unwrap();
+    assert_eq!(decoded, filter);
+}
+
+#[test]
+fn snapshot_keys() {
+    let block = load_block("./test/block1");
+    let tip = block.block_hash();
+    let key = MempoolFilterKey::from_snapshot(&tip, 1);
+    assert_eq!(key, MempoolFilterKey::from_snapshot(&tip, 1));
+    assert_ne!(key, MempoolFilterKey::from_snapshot(&tip, 2));
+    assert_ne!(
+        key,
+        MempoolFilterKey::from_snapshot(&block.header.prev_blockhash, 1)
+    );
+    assert_eq!(MempoolFilterKey::from_bytes(key.to_bytes()), key);
+    assert_ne!(MempoolFilterKey::random(), MempoolFilterKey::random());
+
+    let legacy = MempoolFilterKey::from_bytes(*b"qwertyuiopasdfgh");
+    assert_eq!(legacy.k0(), u64::from_le_bytes(*b"qwertyui"));
+    assert_eq!(legacy.k1(), u64::from_le_bytes(*b"opasdfgh"));
+}
+
+#[test]
+fn outpoint_filter() {
+    let key = MempoolFilterKey::from_bytes(*b"qwertyuiopasdfgh");
+    let block = load_block("./test/block1");
+    let txs = &block.txdata[1..];
+    let filter = ErgveinMempoolFilter::new_outpoint_filter(&key, GcsParams::BIP158, txs).unwrap();
+    for tx in txs {
+        assert_eq!(filter.match_txids(&key, &[tx.txid()]).unwrap(), vec![0]);
+        let spent: Vec<_> = tx.input.iter().map(|i| i.previous_output).collect();
+        assert_eq!(
+            filter.match_outpoints(&key, &spent).unwrap().len(),
+            spent.len()
+        );
+    }
+    let coinbase = block.txdata[0].txid();
+    assert!(filter.match_txids(&key, &[coinbase]).unwrap().is_empty());
+}
@@ -0,0 +1,186 @@
+use crate::btc::ErgveinFilter;
+use crate::policy::FilterId;
+use bitcoin::consensus::{deserialize, encode, Encodable};
+use bitcoin::network::message::NetworkMessage;
+use bitcoin::network::message_filter::{
+    CFCheckpt, CFHeaders, CFilter, GetCFCheckpt, GetCFHeaders, GetCFilters,
+};
+use bitcoin::BlockHash;
+use std::convert::TryFrom;
+use std::error;
+use std::fmt;
+use std::io;
+
+#[cfg(test)]
+mod test;
+
+/// Errors of decoding and checking filter messages
+#[derive(Debug)]
+pub enum Error {
+    /// The command is not a BIP157 one
+    UnknownCommand(String),
+    /// The message is for filters of another type
+    FilterType { expected: u8, actual: u8 },
+    /// Malformed message payload
+    Encode(encode::Error),
+}
+
+impl error::Error for Error {}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::UnknownCommand(command) => write!(f, "unknown filter command {}", command),
+            Error::FilterType { expected, actual } => write!(
+                f,
+                "unexpected filter type {:#04x}, expected {:#04x}",
+                actual, expected
+            ),
+            Error::Encode(e) => write!(f, "malformed filter message: {}", e),
+        }
+    }
+}
+
+impl From<encode::Error> for Error {
+    fn from(e: encode::Error) -> Self {
+        Error::Encode(e)
+    }
+}
+
+/// BIP157 messages, the filter type byte selects ergvein filters instead of the basic ones.
+///
+/// Payloads are encoded exactly as in BIP157, the command name goes to the message header and
+/// is required to decode. Messages convert to `NetworkMessage` of `bitcoin` for framing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FilterMessage {
+    /// Request filters of a block range
+    GetCFilters(GetCFilters),
+    /// Filter of a block
+    CFilter(CFilter),
+    /// Request filter hashes of a block range
+    GetCFHeaders(GetCFHeaders),
+    /// Filter hashes of a block range with the header preceding it
+    CFHeaders(CFHeaders),
+    /// Request filter header checkpoints up to a block
+    GetCFCheckpt(GetCFCheckpt),
+    /// Filter headers of every 1000th block
+    CFCheckpt(CFCheckpt),
+}
+
+impl FilterMessage {
+    /// Create a `cfilter` message for the filter of the given kind
+    pub fn cfilter(id: FilterId, block_hash: BlockHash, filter: &ErgveinFilter) -> FilterMessage {
+        FilterMessage::CFilter(CFilter {
+            filter_type: id.filter_type,
+            block_hash,
+            filter: filter.content.clone(),
+        })
+    }
+
+    /// Command name of the message
+    pub fn command(&self) -> &'static str {
+        match self {
+            FilterMessage::GetCFilters(_) => "getcfilters",
+            FilterMessage::CFilter(_) => "cfilter",
+            FilterMessage::GetCFHeaders(_) => "getcfheaders",
+            FilterMessage::CFHeaders(_) => "cfheaders",
+            FilterMessage::GetCFCheckpt(_) => "getcfcheckpt",
+            FilterMessage::CFCheckpt(_) => "cfcheckpt",
+        }
+    }
+
+    /// Filter type the message is for
+    pub fn filter_type(&self) -> u8 {
+        match self {
+            FilterMessage::GetCFilters(m) => m.filter_type,
+            FilterMessage::CFilter(m) => m.filter_type,
+            FilterMessage::GetCFHeaders(m) => m.filter_type,
+            FilterMessage::CFHeaders(m) => m.filter_type,
+            FilterMessage::GetCFCheckpt(m) => m.filter_type,
+            FilterMessage::CFCheckpt(m) => m.filter_type,
+        }
+    }
+
+    /// Decode the payload of a message with the command
+    pub fn decode(command: &str, payload: &[u8]) -> Result<FilterMessage, Error> {
+        Ok(match command {
+            "getcfilters" => FilterMessage::GetCFilters(deserialize(payload)?),
+            "cfilter" => FilterMessage::CFilter(deserialize(payload)?),
+            "getcfheaders" => FilterMessage::GetCFHeaders(deserialize(payload)?),
+            "cfheaders" => FilterMessage::CFHeaders(deserialize(payload)?),
+            "getcfcheckpt" => FilterMessage::GetCFCheckpt(deserialize(payload)?),
+            "cfcheckpt" => FilterMessage::CFCheckpt(deserialize(payload)?),
+            _ => return Err(Error::UnknownCommand(command.to_owned())),
+        })
+    }
+
+    /// Decode the payload and check that the message is for filters of the kind
+    pub fn decode_for(id: FilterId, command: &str, payload: &[u8]) -> Result<FilterMessage, Error> {
+        let message = FilterMessage::decode(command, payload)?;
+        message.check_filter_type(id)?;
+        Ok(message)
+    }
+
+    /// Check that the message is for filters of the kind
+    pub fn check_filter_type(&self, id: FilterId) -> Result<(), Error> {
+        if self.filter_type() == id.filter_type {
+            Ok(())
+        } else {
+            Err(Error::FilterType {
+                expected: id.filter_type,
+                actual: self.filter_type(),
+            })
+        }
+    }
+
+    /// Extract the block hash and filter of a `cfilter` message
+    pub fn into_filter(self) -> Option<(BlockHash, ErgveinFilter)> {
+        match self {
+            FilterMessage::CFilter(m) => Some((m.block_hash, ErgveinFilter { content: m.filter })),
+            _ => None,
+        }
+    }
+}
+
+impl Encodable for FilterMessage {
+    fn consensus_encode<W: io::Write>(&self, writer: W) -> Result<usize, io::Error> {
+        match self {
+            FilterMessage::GetCFilters(m) => m.consensus_encode(writer),
+            FilterMessage::CFilter(m) => m.consensus_encode(writer),
+            FilterMessage::GetCFHeaders(m) => m.consensus_encode(writer),
+            FilterMessage::CFHeaders(m) => m.consensus_encode(writer),
+            FilterMessage::GetCFCheckpt(m) => m.consensus_encode(writer),
+            FilterMessage::CFCheckpt(m) => m.consensus_encode(writer),
+        }
+    }
+}
+
+impl From<FilterMessage> for NetworkMessage {
+    fn from(message: FilterMessage) -> Self {
+        match message {
+            FilterMessage::GetCFilters(m) => NetworkMessage::GetCFilters(m),
+            FilterMessage::CFilter(m) => NetworkMessage::CFilter(m),
+            FilterMessage::GetCFHeaders(m) => NetworkMessage::GetCFHeaders(m),
+            FilterMessage::CFHeaders(m) => NetworkMessage::CFHeaders(m),
+            FilterMessage::GetCFCheckpt(m) => NetworkMessage::GetCFCheckpt(m),
+            FilterMessage::CFCheckpt(m) => NetworkMessage::CFCheckpt(m),
+        }
+    }
+}
+
+impl TryFrom<NetworkMessage> for FilterMessage {
+    type Error = NetworkMessage;
+
+    /// Other messages are given back as is
+    fn try_from(message: NetworkMessage) -> Result<Self, NetworkMessage> {
+        Ok(match message {
+            NetworkMessage::GetCFilters(m) => FilterMessage::GetCFilters(m),
+            NetworkMessage::CFilter(m) => FilterMessage::CFilter(m),
+            NetworkMessage::GetCFHeaders(m) => FilterMessage::GetCFHeaders(m),
+            NetworkMessage::CFHeaders(m) => FilterMessage::CFHeaders(m),
+            NetworkMessage::GetCFCheckpt(m) => FilterMessage::GetCFCheckpt(m),
+            NetworkMessage::CFCheckpt(m) => FilterMessage::CFCheckpt(m),
+            other => return Err(other),
+        })
+    }
+}
@@ -0,0 +1,123 @@
+use crate::btc::ErgveinFilter;
+use crate::message::{Error, FilterMessage};
+use crate::policy::{
+    Bip158Basic, FilterId, FilterVersion, MissingUtxoPolicy, ScriptPolicy, WitnessAll,
+    WrappedSegwit, ERGVEIN_FILTER_TYPE,
+};
+use crate::test::utils::*;
+use bitcoin::consensus::{deserialize, serialize};
+use bitcoin::network::message::{NetworkMessage, RawNetworkMessage};
+use bitcoin::network::message_filter::{
+    CFCheckpt, CFHeaders, GetCFCheckpt, GetCFHeaders, GetCFilters,
+};
+use bitcoin::{FilterHeader, Network};
+use std::convert::TryFrom;
+
+#[test]
+fn message_roundtrip() {
+    let block = load_block("./test/block1");
+    let block_hash = block.block_hash();
+    let txmap = block1_inputs();
+    let (filter, _) = ErgveinFilter::new_script_filter(
+        &block,
+        &FilterVersion::V0,
+        MissingUtxoPolicy::Fail,
+        script_for_coin(&txmap),
+    )
+    .unwrap();
+    let header = filter.filter_header(&FilterHeader::default());
+
+    let messages = vec![
+        FilterMessage::GetCFilters(GetCFilters {
+            filter_type: ERGVEIN_FILTER_TYPE,
+            start_height: 100,
+            stop_hash: block_hash,
+        }),
+        FilterMessage::cfilter(FilterVersion::V0.id(), block_hash, &filter),
+        FilterMessage::GetCFHeaders(GetCFHeaders {
+            filter_type: ERGVEIN_FILTER_TYPE,
+            start_height: 100,
+            stop_hash: block_hash,
+        }),
+        FilterMessage::CFHeaders(CFHeaders {
+            filter_type: ERGVEIN_FILTER_TYPE,
+            stop_hash: block_hash,
+            previous_filter_header: FilterHeader::default(),
+            filter_hashes: vec![filter.filter_hash(); 3],
+        }),
+        FilterMessage::GetCFCheckpt(GetCFCheckpt {
+            filter_type: ERGVEIN_FILTER_TYPE,
+            stop_hash: block_hash,
+        }),
+        FilterMessage::CFCheckpt(CFCheckpt {
+            filter_type: ERGVEIN_FILTER_TYPE,
+            stop_hash: block_hash,
+            filter_headers: vec![header; 2],
+        }),
+    ];
+    for message in messages {
+        let payload = serialize(&message);
+        let decoded =
+            FilterMessage::decode_for(FilterVersion::V0.id(), message.command(), &payload).unwrap();
+        assert_eq!(message, decoded);
+        // filters of other versions have other filter types
+        for id in [
+            FilterVersion::V1.id(),
+            WrappedSegwit(FilterVersion::V0).id(),
+        ]
+        .iter()
+        {
+            assert!(FilterMessage::decode_for(*id, message.command(), &payload).is_err());
+        }
+        assert!(matches!(
+            message.check_filter_type(Bip158Basic.id()),
+            Err(Error::FilterType {
+                expected: 0,
+                actual: ERGVEIN_FILTER_TYPE
+            })
+        ));
+
+        // framing by bitcoin gives the same payload and command
+        let raw = RawNetworkMessage {
+            magic: Network::Bitcoin.magic(),
+            payload: NetworkMessage::from(message.clone()),
+        };
+        assert_eq!(raw.cmd(), message.command());
+        let bytes = serialize(&raw);
+        assert!(bytes.ends_with(&payload));
+        let raw: RawNetworkMessage = deserialize(&bytes).unwrap();
+        assert_eq!(FilterMessage::try_from(raw.payload).unwrap(), message);
+    }
+
+    let cfilter = FilterMessage::cfilter(FilterVersion::V0.id(), block_hash, &filter);
+    assert_eq!(cfilter.into_filter(), Some((block_hash, filter)));
+    assert!(FilterMessage::try_from(NetworkMessage::Verack).is_err());
+    assert!(matches!(
+        FilterMessage::decode("getdata", &[]),
+        Err(Error::UnknownCommand(_))
+    ));
+    assert!(matches!(
+        FilterMessage::decode("cfilter", &[ERGVEIN_FILTER_TYPE]),
+        Err(Error::Encode(_))
+    ));
+}
+
+#[test]
+fn distinct_filter_types() {
+    let policies: [&dyn ScriptPolicy; 8] = [
+        &Bip158Basic,
+        &FilterVersion::V0,
+        &FilterVersion::V1,
+        &WitnessAll,
+        &WrappedSegwit(Bip158Basic),
+        &WrappedSegwit(FilterVersion::V0),
+        &WrappedSegwit(FilterVersion::V1),
+        &WrappedSegwit(WitnessAll),
+    ];
+    let mut types: Vec<_> = policies.iter().map(|p| p.filter_type()).collect();
+    types.push(FilterId::OUTPOINTS.filter_type);
+    let n_types = types.len();
+    types.sort_unstable();
+    types.dedup();
+    assert_eq!(types.len(), n_types);
+}
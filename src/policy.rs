@@ -0,0 +1,224 @@
+use bitcoin::blockdata::script::Instruction;
+use bitcoin::util::bip158::Error;
+use bitcoin::{OutPoint, Script, TxIn};
+
+/// Filter type of BIP158 basic filters
+pub const BIP158_BASIC_FILTER_TYPE: u8 = 0x00;
+/// Filter type of ergvein filters of `FilterVersion::V0`, outside of the range used by BIP158
+pub const ERGVEIN_FILTER_TYPE: u8 = 0xe0;
+/// Filter type of ergvein filters of `FilterVersion::V1`
+pub const ERGVEIN_V1_FILTER_TYPE: u8 = 0xe3;
+/// Filter type of filters with witness programs only
+pub const WITNESS_ALL_FILTER_TYPE: u8 = 0xe1;
+/// Filter type of filters with spent outpoints and created txids instead of scripts
+pub const OUTPOINT_FILTER_TYPE: u8 = 0xe2;
+/// Bit set in the filter type of a policy wrapped into `WrappedSegwit`
+pub const WRAPPED_SEGWIT_FLAG: u8 = 0x10;
+
+/// Identifies a kind of filters in records and messages: their type and the version of the rules
+/// they are built with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FilterId {
+    /// Filter type byte, as in BIP157 messages
+    pub filter_type: u8,
+    /// Revision of the rules within the filter type
+    pub version: u8,
+}
+
+impl FilterId {
+    /// Outpoint filters with spent outpoints and created txids, see `util::add_tx_outpoints`
+    pub const OUTPOINTS: FilterId = FilterId {
+        filter_type: OUTPOINT_FILTER_TYPE,
+        version: 0,
+    };
+}
+
+/// Rules that decide which scripts are added to a filter.
+///
+/// Both output scripts and scripts spent by inputs are checked with `is_script_indexable`,
+/// inputs are resolved to their spent scripts only if `is_input_indexable` allows it.
+///
+/// Each policy is identified by a filter type and a version, which are stored with serialized
+/// filters. Only the filter type is sent in BIP157 messages, so every set of rules must have its
+/// own filter type. Custom policies must not reuse filter types of the built-in ones, nor set
+/// `WRAPPED_SEGWIT_FLAG` in them.
+pub trait ScriptPolicy {
+    /// Type of filters built with the policy
+    fn filter_type(&self) -> u8;
+
+    /// Revision of the rules within the filter type
+    fn version(&self) -> u8;
+
+    /// Identifier of filters built with the policy
+    fn id(&self) -> FilterId {
+        FilterId {
+            filter_type: self.filter_type(),
+            version: self.version(),
+        }
+    }
+
+    /// Check whether the script is added to a filter
+    fn is_script_indexable(&self, script: &Script) -> bool;
+
+    /// Check whether the script spent by the input should be resolved and added to a filter.
+    ///
+    /// By default only inputs with empty `script_sig` are resolved, as only native segwit
+    /// spends can have an indexable spent script.
+    fn is_input_indexable(&self, input: &TxIn) -> bool {
+        input.script_sig.is_empty()
+    }
+}
+
+/// What to do with an input whose spent script can't be resolved by the lookup
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MissingUtxoPolicy {
+    /// Fail building the filter with the lookup error
+    Fail,
+    /// Silently leave the spent script out of the filter
+    Skip,
+    /// Leave the spent script out of the filter and report the outpoint
+    Collect,
+}
+
+impl MissingUtxoPolicy {
+    /// Handle a failed lookup of the outpoint, collected outpoints are pushed to `skipped`
+    pub fn handle(
+        &self,
+        outpoint: &OutPoint,
+        error: Error,
+        skipped: &mut Vec<OutPoint>,
+    ) -> Result<(), Error> {
+        match self {
+            MissingUtxoPolicy::Fail => Err(error),
+            MissingUtxoPolicy::Skip => Ok(()),
+            MissingUtxoPolicy::Collect => {
+                skipped.push(*outpoint);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Version of the ergvein rules that decide which scripts are added to a filter.
+///
+/// Filters built with different versions are not interchangeable, so the version used
+/// by a server must be known to its clients.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FilterVersion {
+    /// Segwit v0 scripts (P2WPKH and P2WSH) and data carriers
+    V0,
+    /// Witness programs of any version (v0, Taproot and future v2-v16) and data carriers
+    V1,
+}
+
+impl ScriptPolicy for FilterVersion {
+    fn filter_type(&self) -> u8 {
+        match self {
+            FilterVersion::V0 => ERGVEIN_FILTER_TYPE,
+            FilterVersion::V1 => ERGVEIN_V1_FILTER_TYPE,
+        }
+    }
+
+    fn version(&self) -> u8 {
+        match self {
+            FilterVersion::V0 => 0,
+            FilterVersion::V1 => 1,
+        }
+    }
+
+    fn is_script_indexable(&self, script: &Script) -> bool {
+        if script.is_empty() {
+            return false;
+        }
+        match self {
+            FilterVersion::V0 => {
+                script.is_v0_p2wsh() || script.is_v0_p2wpkh() || script.is_op_return()
+            }
+            FilterVersion::V1 => script.is_witness_program() || script.is_op_return(),
+        }
+    }
+}
+
+/// Full BIP158 basic filter: every output script except data carriers and every spent script.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Bip158Basic;
+
+impl ScriptPolicy for Bip158Basic {
+    fn filter_type(&self) -> u8 {
+        BIP158_BASIC_FILTER_TYPE
+    }
+
+    fn version(&self) -> u8 {
+        0
+    }
+
+    fn is_script_indexable(&self, script: &Script) -> bool {
+        !script.is_empty() && !script.is_op_return()
+    }
+
+    fn is_input_indexable(&self, _input: &TxIn) -> bool {
+        true
+    }
+}
+
+/// Witness programs of any version without data carriers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct WitnessAll;
+
+impl ScriptPolicy for WitnessAll {
+    fn filter_type(&self) -> u8 {
+        WITNESS_ALL_FILTER_TYPE
+    }
+
+    fn version(&self) -> u8 {
+        0
+    }
+
+    fn is_script_indexable(&self, script: &Script) -> bool {
+        script.is_witness_program()
+    }
+}
+
+/// Extends another policy with nested segwit: P2SH outputs and P2SH-P2WPKH or P2SH-P2WSH spends.
+///
+/// P2SH outputs can't be told apart from legacy ones until they are spent, so all of them are
+/// indexed. Of inputs only wrapped segwit spends are added besides the ones of the inner policy.
+///
+/// The filter type is the one of the inner policy with `WRAPPED_SEGWIT_FLAG` set, the version has
+/// the high bit set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct WrappedSegwit<P>(pub P);
+
+impl<P: ScriptPolicy> ScriptPolicy for WrappedSegwit<P> {
+    fn filter_type(&self) -> u8 {
+        self.0.filter_type() | WRAPPED_SEGWIT_FLAG
+    }
+
+    fn version(&self) -> u8 {
+        self.0.version() | 0x80
+    }
+
+    fn is_script_indexable(&self, script: &Script) -> bool {
+        script.is_p2sh() || self.0.is_script_indexable(script)
+    }
+
+    fn is_input_indexable(&self, input: &TxIn) -> bool {
+        is_wrapped_segwit_input(input) || self.0.is_input_indexable(input)
+    }
+}
+
+/// Check whether the input spends a P2SH-P2WPKH or P2SH-P2WSH output, i.e. its `script_sig` is
+/// a single push of a segwit v0 redeem script and its witness is not empty.
+pub fn is_wrapped_segwit_input(input: &TxIn) -> bool {
+    if input.witness.is_empty() {
+        return false;
+    }
+    let mut instructions = input.script_sig.instructions();
+    match (instructions.next(), instructions.next()) {
+        (Some(Ok(Instruction::PushBytes(redeem))), None) => {
+            let redeem = Script::from(redeem.to_vec());
+            redeem.is_v0_p2wpkh() || redeem.is_v0_p2wsh()
+        }
+        _ => false,
+    }
+}
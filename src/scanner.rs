@@ -0,0 +1,133 @@
+use crate::btc::ErgveinFilter;
+use crate::gcs::{self, GcsFilterReader};
+use crate::util::{block_filter_keys, GcsParams};
+use bitcoin::util::bip158::Error;
+use bitcoin::BlockHash;
+use std::borrow::Borrow;
+
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+
+#[cfg(test)]
+mod test;
+
+/// Matches one watch list against many block filters, e.g. to rescan a wallet.
+///
+/// The watch list is deduplicated once, each filter is then read in a single pass reusing the
+/// same buffer for hashed query elements. Empty watch list never matches.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilterScanner {
+    watch: Vec<Vec<u8>>,
+}
+
+impl FilterScanner {
+    /// Create a scanner for the watched elements, usually output scripts of a wallet
+    pub fn new<I, T>(watch: I) -> FilterScanner
+    where
+        I: IntoIterator<Item = T>,
+        T: AsRef<[u8]>,
+    {
+        let mut watch: Vec<Vec<u8>> = watch
+            .into_iter()
+            .map(|e| e.as_ref().to_vec())
+            .filter(|e| !e.is_empty())
+            .collect();
+        watch.sort_unstable();
+        watch.dedup();
+        FilterScanner { watch }
+    }
+
+    /// Amount of distinct watched elements
+    pub fn len(&self) -> usize {
+        self.watch.len()
+    }
+
+    /// Check whether the watch list is empty
+    pub fn is_empty(&self) -> bool {
+        self.watch.is_empty()
+    }
+
+    /// Check whether any watched element is in the filter of the block
+    pub fn matches(&self, block_hash: &BlockHash, filter: &ErgveinFilter) -> Result<bool, Error> {
+        self.matches_with(
+            block_hash,
+            filter,
+            &mut Vec::with_capacity(self.watch.len()),
+        )
+    }
+
+    fn matches_with(
+        &self,
+        block_hash: &BlockHash,
+        filter: &ErgveinFilter,
+        mapped: &mut Vec<u64>,
+    ) -> Result<bool, Error> {
+        if self.watch.is_empty() {
+            return Ok(false);
+        }
+        let (k0, k1) = block_filter_keys(block_hash);
+        let reader = GcsFilterReader::new(k0, k1, GcsParams::BIP158);
+        let mut values = reader.values(filter.content.as_slice());
+        let nm = reader.range(values.remaining())?;
+        mapped.clear();
+        mapped.extend(
+            self.watch
+                .iter()
+                .map(|e| gcs::map_to_range(gcs::hash_element(k0, k1, e), nm)),
+        );
+        mapped.sort_unstable();
+
+        let mut data = match values.next() {
+            Some(data) => data?,
+            None => return Ok(false),
+        };
+        for &p in mapped.iter() {
+            while data < p {
+                data = match values.next() {
+                    Some(data) => data?,
+                    None => return Ok(false),
+                };
+            }
+            if data == p {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Return hashes of blocks whose filters match the watch list, in the order of the input
+    pub fn scan<I, F>(&self, filters: I) -> Result<Vec<BlockHash>, Error>
+    where
+        I: IntoIterator<Item = (BlockHash, F)>,
+        F: Borrow<ErgveinFilter>,
+    {
+        let mut mapped = Vec::with_capacity(self.watch.len());
+        let mut matched = vec![];
+        for (block_hash, filter) in filters {
+            if self.matches_with(&block_hash, filter.borrow(), &mut mapped)? {
+                matched.push(block_hash);
+            }
+        }
+        Ok(matched)
+    }
+
+    /// Same as `scan`, but filters are matched in parallel on the rayon thread pool
+    #[cfg(feature = "rayon")]
+    pub fn par_scan<I, F>(&self, filters: I) -> Result<Vec<BlockHash>, Error>
+    where
+        I: IntoParallelIterator<Item = (BlockHash, F)>,
+        F: Borrow<ErgveinFilter> + Send,
+    {
+        let matched: Vec<Option<BlockHash>> = filters
+            .into_par_iter()
+            .map_init(
+                || Vec::with_capacity(self.watch.len()),
+                |mapped, (block_hash, filter)| {
+                    self.matches_with(&block_hash, filter.borrow(), mapped)
+                        .map(|m| if m { Some(block_hash) } else { None })
+                },
+            )
+            .collect::<Result<_, _>>()?;
+        Ok(matched.into_iter().flatten().collect())
+    }
+}
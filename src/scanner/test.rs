@@ -0,0 +1,64 @@
+use crate::btc::ErgveinFilter;
+use crate::builder::FilterBuilder;
+use crate::policy::{FilterVersion, MissingUtxoPolicy};
+use crate::scanner::FilterScanner;
+use bitcoin::hashes::{sha256d, Hash};
+use bitcoin::BlockHash;
+
+fn make_filters(n: u32) -> Vec<(BlockHash, ErgveinFilter)> {
+    (0..n)
+        .map(|i| {
+            let block_hash = BlockHash::from_hash(sha256d::Hash::hash(&i.to_le_bytes()));
+            let mut builder =
+                FilterBuilder::for_block(&block_hash, &FilterVersion::V0, MissingUtxoPolicy::Fail);
+            for j in 0..20u32 {
+                builder.add_element(format!("block {} element {}", i, j).as_bytes());
+            }
+            if i % 7 == 3 {
+                builder.add_element(b"watched");
+            }
+            let filter = ErgveinFilter::new(&builder.finish().unwrap());
+            (block_hash, filter)
+        })
+        .collect()
+}
+
+#[test]
+fn scan_range() {
+    let filters = make_filters(100);
+    let expected: Vec<BlockHash> = filters
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| i % 7 == 3)
+        .map(|(_, (h, _))| *h)
+        .collect();
+
+    let scanner = FilterScanner::new(vec![
+        b"watched".to_vec(),
+        b"watched".to_vec(),
+        b"unknown".to_vec(),
+        vec![],
+    ]);
+    assert_eq!(scanner.len(), 2);
+    let matched = scanner.scan(filters.iter().map(|(h, f)| (*h, f))).unwrap();
+    assert_eq!(expected, matched);
+    assert!(scanner.matches(&filters[3].0, &filters[3].1).unwrap());
+    assert!(!scanner.matches(&filters[4].0, &filters[4].1).unwrap());
+
+    let single = FilterScanner::new(["block 42 element 7"]);
+    assert_eq!(vec![filters[42].0], single.scan(filters.clone()).unwrap());
+
+    let empty = FilterScanner::new(Vec::<Vec<u8>>::new());
+    assert!(empty.is_empty());
+    assert!(empty.scan(filters).unwrap().is_empty());
+}
+
+#[cfg(feature = "rayon")]
+#[test]
+fn par_scan_range() {
+    let filters = make_filters(300);
+    let scanner = FilterScanner::new(["watched", "block 250 element 0"]);
+    let expected = scanner.scan(filters.iter().map(|(h, f)| (*h, f))).unwrap();
+    assert_eq!(expected.len(), 44);
+    assert_eq!(expected, scanner.par_scan(filters).unwrap());
+}
@@ -0,0 +1,225 @@
+use crate::btc::ErgveinFilter;
+use bitcoin::consensus::encode;
+use bitcoin::{BlockHash, FilterHeader};
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::io;
+
+pub mod flat;
+
+pub use flat::FlatFileStore;
+
+#[cfg(test)]
+mod test;
+
+/// Errors of filter stores
+#[derive(Debug)]
+pub enum Error {
+    /// Filters are stored only in order of height without gaps
+    NotNextHeight { expected: u32, actual: u32 },
+    /// The store has no filter at the height
+    UnknownHeight(u32),
+    /// Failed to read or write the underlying files
+    Io(io::Error),
+    /// Stored data is malformed
+    Encode(encode::Error),
+}
+
+impl error::Error for Error {}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::NotNextHeight { expected, actual } => write!(
+                f,
+                "filter at height {} can't be stored, the next height is {}",
+                actual, expected
+            ),
+            Error::UnknownHeight(height) => write!(f, "no filter at height {}", height),
+            Error::Io(e) => write!(f, "filter store I/O error: {}", e),
+            Error::Encode(e) => write!(f, "malformed stored filter: {}", e),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<encode::Error> for Error {
+    fn from(e: encode::Error) -> Self {
+        Error::Encode(e)
+    }
+}
+
+/// A filter with its position in the chain
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredFilter {
+    /// Height of the block
+    pub height: u32,
+    /// Hash of the block
+    pub block_hash: BlockHash,
+    /// Header of the filter in the chain of filter headers
+    pub header: FilterHeader,
+    /// The filter itself
+    pub filter: ErgveinFilter,
+}
+
+/// Persistence of filters and filter headers of one chain.
+///
+/// Filters are appended one height after another, the first one can be at any height, e.g. when
+/// a client starts from a checkpoint. Disconnected blocks are dropped with `rewind`.
+pub trait FilterStore {
+    /// Append the filter of the block at the next height with its header
+    fn put(
+        &mut self,
+        height: u32,
+        block_hash: BlockHash,
+        filter: &ErgveinFilter,
+        header: FilterHeader,
+    ) -> Result<(), Error>;
+
+    /// Get the filter of the block at the height
+    fn get(&self, height: u32) -> Result<Option<StoredFilter>, Error>;
+
+    /// Height of the block, if its filter is stored
+    fn height_of(&self, block_hash: &BlockHash) -> Option<u32>;
+
+    /// Header of the filter at the height
+    fn header(&self, height: u32) -> Option<FilterHeader>;
+
+    /// Height, block hash and filter header of the last stored filter
+    fn tip(&self) -> Option<(u32, BlockHash, FilterHeader)>;
+
+    /// Drop all filters above the height, rewinding to the height just below the first filter
+    /// empties the store
+    fn rewind(&mut self, height: u32) -> Result<(), Error>;
+
+    /// Get the filter of the block by its hash
+    fn get_by_hash(&self, block_hash: &BlockHash) -> Result<Option<StoredFilter>, Error> {
+        match self.height_of(block_hash) {
+            Some(height) => self.get(height),
+            None => Ok(None),
+        }
+    }
+
+    /// Append the filter of the next block computing its header from the tip, returns the header.
+    ///
+    /// An empty store starts from genesis.
+    fn push(
+        &mut self,
+        block_hash: BlockHash,
+        filter: &ErgveinFilter,
+    ) -> Result<FilterHeader, Error> {
+        let (height, prev) = match self.tip() {
+            Some((height, _, header)) => (height + 1, header),
+            None => (0, FilterHeader::default()),
+        };
+        let header = filter.filter_header(&prev);
+        self.put(height, block_hash, filter, header)?;
+        Ok(header)
+    }
+}
+
+/// Checks that a filter at `height` can be appended to a store with the tip
+pub(crate) fn check_next_height(tip: Option<u32>, height: u32) -> Result<(), Error> {
+    match tip {
+        Some(tip) if tip.checked_add(1) != Some(height) => Err(Error::NotNextHeight {
+            expected: tip + 1,
+            actual: height,
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Amount of filters left after rewinding a store to the height
+pub(crate) fn rewind_len(start_height: u32, len: usize, height: u32) -> Result<usize, Error> {
+    let kept = (height as u64 + 1)
+        .checked_sub(start_height as u64)
+        .filter(|kept| *kept <= len as u64)
+        .ok_or(Error::UnknownHeight(height))?;
+    Ok(kept as usize)
+}
+
+/// Store that keeps everything in memory
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemoryFilterStore {
+    start_height: u32,
+    filters: Vec<StoredFilter>,
+    heights: HashMap<BlockHash, u32>,
+}
+
+impl MemoryFilterStore {
+    /// Create an empty store
+    pub fn new() -> MemoryFilterStore {
+        MemoryFilterStore::default()
+    }
+
+    /// Amount of stored filters
+    pub fn len(&self) -> usize {
+        self.filters.len()
+    }
+
+    /// Check whether no filters are stored
+    pub fn is_empty(&self) -> bool {
+        self.filters.is_empty()
+    }
+
+    fn entry(&self, height: u32) -> Option<&StoredFilter> {
+        height
+            .checked_sub(self.start_height)
+            .and_then(|i| self.filters.get(i as usize))
+    }
+}
+
+impl FilterStore for MemoryFilterStore {
+    fn put(
+        &mut self,
+        height: u32,
+        block_hash: BlockHash,
+        filter: &ErgveinFilter,
+        header: FilterHeader,
+    ) -> Result<(), Error> {
+        check_next_height(self.tip().map(|t| t.0), height)?;
+        if self.filters.is_empty() {
+            self.start_height = height;
+        }
+        self.heights.insert(block_hash, height);
+        self.filters.push(StoredFilter {
+            height,
+            block_hash,
+            header,
+            filter: filter.clone(),
+        });
+        Ok(())
+    }
+
+    fn get(&self, height: u32) -> Result<Option<StoredFilter>, Error> {
+        Ok(self.entry(height).cloned())
+    }
+
+    fn height_of(&self, block_hash: &BlockHash) -> Option<u32> {
+        self.heights.get(block_hash).copied()
+    }
+
+    fn header(&self, height: u32) -> Option<FilterHeader> {
+        self.entry(height).map(|e| e.header)
+    }
+
+    fn tip(&self) -> Option<(u32, BlockHash, FilterHeader)> {
+        self.filters
+            .last()
+            .map(|e| (e.height, e.block_hash, e.header))
+    }
+
+    fn rewind(&mut self, height: u32) -> Result<(), Error> {
+        let len = rewind_len(self.start_height, self.filters.len(), height)?;
+        for entry in self.filters.drain(len..) {
+            self.heights.remove(&entry.block_hash);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,253 @@
+use crate::btc::ErgveinFilter;
+use crate::store::{check_next_height, rewind_len, Error, FilterStore, StoredFilter};
+use bitcoin::consensus::{deserialize, encode, serialize, Decodable, Encodable};
+use bitcoin::{BlockHash, FilterHeader};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+/// Name of the file with encoded filters in the store directory
+pub const DATA_FILE: &str = "filters.dat";
+/// Name of the index file in the store directory
+pub const INDEX_FILE: &str = "filters.idx";
+
+/// Size of the index file header: height of the first filter
+const INDEX_HEADER_SIZE: u64 = 4;
+/// Size of an encoded `IndexEntry`
+const INDEX_ENTRY_SIZE: u64 = 8 + 4 + 32 + 32;
+
+/// Position of a filter in the data file with its block hash and header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct IndexEntry {
+    offset: u64,
+    size: u32,
+    block_hash: BlockHash,
+    header: FilterHeader,
+}
+
+impl Encodable for IndexEntry {
+    fn consensus_encode<W: io::Write>(&self, mut writer: W) -> Result<usize, io::Error> {
+        let mut len = self.offset.consensus_encode(&mut writer)?;
+        len += self.size.consensus_encode(&mut writer)?;
+        len += self.block_hash.consensus_encode(&mut writer)?;
+        len += self.header.consensus_encode(&mut writer)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for IndexEntry {
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<Self, encode::Error> {
+        Ok(IndexEntry {
+            offset: Decodable::consensus_decode(&mut d)?,
+            size: Decodable::consensus_decode(&mut d)?,
+            block_hash: Decodable::consensus_decode(&mut d)?,
+            header: Decodable::consensus_decode(&mut d)?,
+        })
+    }
+}
+
+/// Append-only store in two files of a directory: encoded filters one after another and an
+/// index with a fixed size entry per height.
+///
+/// The index is loaded in memory on open, filters are read from disk on request. Reads lock the
+/// data file, so a shared store can be read from several threads. Filters and
+/// index entries written partially, e.g. on a crash, are dropped on open. Rewind truncates both
+/// files.
+#[derive(Debug)]
+pub struct FlatFileStore {
+    dir: PathBuf,
+    data: Mutex<File>,
+    index: File,
+    start_height: u32,
+    entries: Vec<IndexEntry>,
+    heights: HashMap<BlockHash, u32>,
+}
+
+impl FlatFileStore {
+    /// Open the store in the directory, it is created if missing
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<FlatFileStore, Error> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let open = |name| {
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(dir.join(name))
+        };
+        let data = open(DATA_FILE)?;
+        let mut index = open(INDEX_FILE)?;
+
+        let mut bytes = vec![];
+        index.read_to_end(&mut bytes)?;
+        let mut start_height = 0;
+        let mut entries = vec![];
+        if bytes.len() as u64 >= INDEX_HEADER_SIZE {
+            let mut reader = bytes.as_slice();
+            start_height = Decodable::consensus_decode(&mut reader)?;
+            let n_entries = reader.len() / INDEX_ENTRY_SIZE as usize;
+            for _ in 0..n_entries {
+                entries.push(IndexEntry::consensus_decode(&mut reader)?);
+            }
+        }
+        // drop entries pointing past the end of data
+        let data_len = data.metadata()?.len();
+        while let Some(last) = entries.last() {
+            if last.offset + last.size as u64 <= data_len {
+                break;
+            }
+            entries.pop();
+        }
+        let mut store = FlatFileStore {
+            dir,
+            data: Mutex::new(data),
+            index,
+            start_height,
+            entries,
+            heights: HashMap::new(),
+        };
+        store.truncate(store.entries.len())?;
+        store.heights = (store.start_height..)
+            .zip(store.entries.iter())
+            .map(|(height, e)| (e.block_hash, height))
+            .collect();
+        Ok(store)
+    }
+
+    /// Directory of the store
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Amount of stored filters
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Check whether no filters are stored
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Flush written filters to the disk
+    pub fn sync(&self) -> Result<(), Error> {
+        self.data().sync_data()?;
+        self.index.sync_data()?;
+        Ok(())
+    }
+
+    /// The data file, a panic of another reader leaves it usable as every read seeks first
+    fn data(&self) -> MutexGuard<'_, File> {
+        self.data.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn data_mut(&mut self) -> &mut File {
+        self.data.get_mut().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn entry(&self, height: u32) -> Option<&IndexEntry> {
+        height
+            .checked_sub(self.start_height)
+            .and_then(|i| self.entries.get(i as usize))
+    }
+
+    fn data_len(&self) -> u64 {
+        self.entries
+            .last()
+            .map(|e| e.offset + e.size as u64)
+            .unwrap_or(0)
+    }
+
+    /// Keep only the first `len` entries in memory and on disk
+    fn truncate(&mut self, len: usize) -> Result<(), Error> {
+        for entry in self.entries.drain(len..) {
+            self.heights.remove(&entry.block_hash);
+        }
+        let index_len = if self.entries.is_empty() {
+            0
+        } else {
+            INDEX_HEADER_SIZE + self.entries.len() as u64 * INDEX_ENTRY_SIZE
+        };
+        self.index.set_len(index_len)?;
+        let data_len = self.data_len();
+        self.data_mut().set_len(data_len)?;
+        Ok(())
+    }
+}
+
+impl FilterStore for FlatFileStore {
+    fn put(
+        &mut self,
+        height: u32,
+        block_hash: BlockHash,
+        filter: &ErgveinFilter,
+        header: FilterHeader,
+    ) -> Result<(), Error> {
+        check_next_height(self.tip().map(|t| t.0), height)?;
+        if self.entries.is_empty() {
+            self.start_height = height;
+            self.index.seek(SeekFrom::Start(0))?;
+            self.index.write_all(&serialize(&height))?;
+        }
+        let bytes = serialize(filter);
+        let entry = IndexEntry {
+            offset: self.data_len(),
+            size: bytes.len() as u32,
+            block_hash,
+            header,
+        };
+        let data = self.data_mut();
+        data.seek(SeekFrom::Start(entry.offset))?;
+        data.write_all(&bytes)?;
+        self.index.seek(SeekFrom::Start(
+            INDEX_HEADER_SIZE + self.entries.len() as u64 * INDEX_ENTRY_SIZE,
+        ))?;
+        self.index.write_all(&serialize(&entry))?;
+        self.entries.push(entry);
+        self.heights.insert(block_hash, height);
+        Ok(())
+    }
+
+    fn get(&self, height: u32) -> Result<Option<StoredFilter>, Error> {
+        let entry = match self.entry(height) {
+            Some(entry) => *entry,
+            None => return Ok(None),
+        };
+        let mut bytes = vec![0; entry.size as usize];
+        let mut data = self.data();
+        data.seek(SeekFrom::Start(entry.offset))?;
+        data.read_exact(&mut bytes)?;
+        Ok(Some(StoredFilter {
+            height,
+            block_hash: entry.block_hash,
+            header: entry.header,
+            filter: deserialize(&bytes)?,
+        }))
+    }
+
+    fn height_of(&self, block_hash: &BlockHash) -> Option<u32> {
+        self.heights.get(block_hash).copied()
+    }
+
+    fn header(&self, height: u32) -> Option<FilterHeader> {
+        self.entry(height).map(|e| e.header)
+    }
+
+    fn tip(&self) -> Option<(u32, BlockHash, FilterHeader)> {
+        self.entries.last().map(|e| {
+            (
+                self.start_height + self.entries.len() as u32 - 1,
+                e.block_hash,
+                e.header,
+            )
+        })
+    }
+
+    fn rewind(&mut self, height: u32) -> Result<(), Error> {
+        let len = rewind_len(self.start_height, self.entries.len(), height)?;
+        self.truncate(len)
+    }
+}
@@ -0,0 +1,159 @@
+use crate::btc::ErgveinFilter;
+use crate::header::FilterHeaderChain;
+use crate::store::flat::{DATA_FILE, INDEX_FILE};
+use crate::store::{Error, FilterStore, FlatFileStore, MemoryFilterStore};
+use crate::test::utils::temp_dir;
+use bitcoin::hashes::{sha256d, Hash};
+use bitcoin::{BlockHash, FilterHeader};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+
+fn make_block(i: u32) -> (BlockHash, ErgveinFilter) {
+    let block_hash = BlockHash::from_hash(sha256d::Hash::hash(&i.to_le_bytes()));
+    let mut content = vec![1];
+    content.extend_from_slice(&i.to_le_bytes());
+    (block_hash, ErgveinFilter::new(&content))
+}
+
+fn check_store<S: FilterStore>(store: &mut S) {
+    assert_eq!(store.tip(), None);
+    let mut chain = FilterHeaderChain::new();
+    for i in 0..10 {
+        let (block_hash, filter) = make_block(i);
+        assert_eq!(
+            store.push(block_hash, &filter).unwrap(),
+            chain.push(&filter)
+        );
+    }
+    let (hash7, filter7) = make_block(7);
+    let stored = store.get(7).unwrap().unwrap();
+    assert_eq!(stored.height, 7);
+    assert_eq!(stored.block_hash, hash7);
+    assert_eq!(stored.filter, filter7);
+    assert_eq!(Some(stored.header), chain.header(7));
+    assert_eq!(store.get_by_hash(&hash7).unwrap().unwrap().height, 7);
+    assert_eq!(store.header(9), chain.header(9));
+    assert_eq!(store.tip(), Some((9, make_block(9).0, chain.tip())));
+    assert!(store.get(10).unwrap().is_none());
+    assert!(matches!(
+        store.put(12, hash7, &filter7, FilterHeader::default()),
+        Err(Error::NotNextHeight {
+            expected: 10,
+            actual: 12
+        })
+    ));
+
+    store.rewind(6).unwrap();
+    assert_eq!(store.tip().unwrap().0, 6);
+    assert!(store.get(7).unwrap().is_none());
+    assert_eq!(store.height_of(&hash7), None);
+    assert!(matches!(store.rewind(8), Err(Error::UnknownHeight(8))));
+
+    // another branch
+    let (block_hash, filter) = make_block(100);
+    let header = store.push(block_hash, &filter).unwrap();
+    assert_eq!(header, filter.filter_header(&chain.header(6).unwrap()));
+    assert_eq!(store.height_of(&block_hash), Some(7));
+    assert_eq!(store.get(7).unwrap().unwrap().filter, filter);
+}
+
+#[test]
+fn memory_store() {
+    let mut store = MemoryFilterStore::new();
+    check_store(&mut store);
+    assert_eq!(store.len(), 8);
+}
+
+#[test]
+fn flat_file_store() {
+    let dir = temp_dir("flat");
+    {
+        let mut store = FlatFileStore::open(&dir).unwrap();
+        check_store(&mut store);
+        store.sync().unwrap();
+    }
+    let store = FlatFileStore::open(&dir).unwrap();
+    assert_eq!(store.len(), 8);
+    assert_eq!(store.tip().unwrap().0, 7);
+    assert_eq!(store.get(7).unwrap().unwrap().filter, make_block(100).1);
+    assert_eq!(store.get(3).unwrap().unwrap().filter, make_block(3).1);
+    drop(store);
+
+    // partially written filter and index entry are dropped
+    for name in &[DATA_FILE, INDEX_FILE] {
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(dir.join(name))
+            .unwrap();
+        file.write_all(&[0xff; 50]).unwrap();
+    }
+    let mut store = FlatFileStore::open(&dir).unwrap();
+    assert_eq!(store.len(), 8);
+    let (block_hash, filter) = make_block(8);
+    store.push(block_hash, &filter).unwrap();
+    assert_eq!(store.get(8).unwrap().unwrap().filter, filter);
+    drop(store);
+
+    let store = FlatFileStore::open(&dir).unwrap();
+    assert_eq!(store.get_by_hash(&block_hash).unwrap().unwrap().height, 8);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn flat_file_concurrent_reads() {
+    let dir = temp_dir("concurrent");
+    let mut store = FlatFileStore::open(&dir).unwrap();
+    for i in 0..20 {
+        let (block_hash, filter) = make_block(i);
+        store.push(block_hash, &filter).unwrap();
+    }
+    let store = &store;
+    std::thread::scope(|scope| {
+        for t in 0..4 {
+            scope.spawn(move || {
+                for round in 0..50 {
+                    let height = (t * 7 + round) % 20;
+                    let stored = store.get(height).unwrap().unwrap();
+                    assert_eq!(stored.filter, make_block(height).1);
+                }
+            });
+        }
+    });
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn store_from_checkpoint() {
+    let dir = temp_dir("checkpoint");
+    let checkpoint = FilterHeader::from_hash(sha256d::Hash::hash(b"checkpoint"));
+    let mut chain = FilterHeaderChain::from_checkpoint(4999, checkpoint).unwrap();
+    let mut memory = MemoryFilterStore::new();
+    {
+        let mut flat = FlatFileStore::open(&dir).unwrap();
+        for i in 5000..5010 {
+            let (block_hash, filter) = make_block(i);
+            let header = chain.push(&filter);
+            memory.put(i, block_hash, &filter, header).unwrap();
+            flat.put(i, block_hash, &filter, header).unwrap();
+        }
+        assert!(flat.get(4999).unwrap().is_none());
+        assert!(matches!(flat.rewind(4000), Err(Error::UnknownHeight(4000))));
+    }
+    let mut flat = FlatFileStore::open(&dir).unwrap();
+    for store in [&mut memory as &mut dyn FilterStore, &mut flat] {
+        assert_eq!(store.tip().unwrap().0, 5009);
+        assert_eq!(store.header(5005), chain.header(5005));
+        let (block_hash, filter) = make_block(5010);
+        assert_eq!(
+            store.push(block_hash, &filter).unwrap(),
+            filter.filter_header(&chain.tip())
+        );
+        // rewind below the first filter empties the store
+        store.rewind(4999).unwrap();
+        assert_eq!(store.tip(), None);
+    }
+    assert!(flat.is_empty());
+    drop(flat);
+    assert!(FlatFileStore::open(&dir).unwrap().is_empty());
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -1,5 +1,6 @@
 use bitcoin::consensus::deserialize;
 use bitcoin::hashes::hex::FromHex;
+use bitcoin::util::bip158;
 use bitcoin::Block;
 use bitcoin::OutPoint;
 use bitcoin::Script;
@@ -8,6 +9,7 @@ use std::collections::HashMap;
 use std::fs;
 use std::io;
 use std::io::BufRead;
+use std::path::PathBuf;
 
 pub fn make_inputs_map(txs: Vec<Transaction>) -> HashMap<OutPoint, Script> {
     let mut map = HashMap::new();
@@ -18,7 +20,7 @@ pub fn make_inputs_map(txs: Vec<Transaction>) -> HashMap<OutPoint, Script> {
         };
         for (i, out) in tx.output.iter().enumerate() {
             out_point.vout = i as u32;
-            map.insert(out_point.clone(), out.script_pubkey.clone());
+            map.insert(out_point, out.script_pubkey.clone());
         }
     }
     map
@@ -39,3 +41,31 @@ pub fn load_txs(path: &str) -> Vec<Transaction> {
     }
     res
 }
+
+/// Scripts spent by transactions of `./test/block1`
+pub fn block1_inputs() -> HashMap<OutPoint, Script> {
+    make_inputs_map(load_txs("./test/block1-txs"))
+}
+
+/// Scripts spent by non-coinbase transactions of a block in order of inputs
+pub fn block_prevouts(block: &Block, map: &HashMap<OutPoint, Script>) -> Vec<Script> {
+    block.txdata[1..]
+        .iter()
+        .flat_map(|tx| tx.input.iter().map(|i| map[&i.previous_output].clone()))
+        .collect()
+}
+
+/// Resolver of spent scripts from a map, failing on unknown outpoints
+pub fn script_for_coin(
+    map: &HashMap<OutPoint, Script>,
+) -> impl Fn(&OutPoint) -> Result<Script, bip158::Error> + Copy + '_ {
+    move |o| map.get(o).cloned().ok_or(bip158::Error::UtxoMissing(*o))
+}
+
+/// Create an empty temporary directory unique for the test process
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("ergvein-filters-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
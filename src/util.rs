@@ -1,74 +1,245 @@
+use crate::policy::{FilterVersion, ScriptPolicy};
 use bitcoin::{
-    util::bip158::{BlockFilterWriter, Error},
-    OutPoint, Script, Transaction,
+    consensus::{encode, Decodable, Encodable},
+    util::bip158::Error,
+    BlockHash, OutPoint, Script, Transaction,
 };
+use std::{error, fmt, io};
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// Golomb-Rice coding parameters of a filter.
+///
+/// Smaller `p` and `m` make filters smaller at the cost of a higher false positive rate, which
+/// is about `1/m`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "RawGcsParams"))]
+pub struct GcsParams {
+    /// Amount of bits in the remainder of Golomb-Rice codes
+    pub p: u8,
+    /// Inverse of the false positive rate
+    pub m: u64,
+}
+
+/// Deserialized fields of `GcsParams` before they are checked
+#[cfg(feature = "serde")]
+#[derive(Deserialize)]
+struct RawGcsParams {
+    p: u8,
+    m: u64,
+}
+
+#[cfg(feature = "serde")]
+impl std::convert::TryFrom<RawGcsParams> for GcsParams {
+    type Error = ParamsError;
+
+    fn try_from(raw: RawGcsParams) -> Result<Self, Self::Error> {
+        GcsParams::new(raw.p, raw.m)
+    }
+}
+
+impl GcsParams {
+    /// Golomb encoding parameter as in BIP-158, see also https://gist.github.com/sipa/576d5f09c3b86c3b1b75598d799fc845
+    pub const BIP158: GcsParams = GcsParams { p: 19, m: 784931 };
+
+    /// Largest supported amount of remainder bits
+    pub const MAX_P: u8 = 32;
+
+    /// Create parameters, `p` must be in `1..=MAX_P` and `m` must not be zero
+    pub fn new(p: u8, m: u64) -> Result<GcsParams, ParamsError> {
+        if p == 0 || p > GcsParams::MAX_P {
+            return Err(ParamsError::P(p));
+        }
+        if m == 0 {
+            return Err(ParamsError::ZeroM);
+        }
+        Ok(GcsParams { p, m })
+    }
+}
+
+/// Reasons to reject Golomb-Rice coding parameters
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParamsError {
+    /// Amount of remainder bits is out of `1..=GcsParams::MAX_P`
+    P(u8),
+    /// Zero inverse false positive rate
+    ZeroM,
+}
+
+impl error::Error for ParamsError {}
+
+impl fmt::Display for ParamsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParamsError::P(p) => write!(
+                f,
+                "Golomb-Rice parameter p = {} is out of 1..={}",
+                p,
+                GcsParams::MAX_P
+            ),
+            ParamsError::ZeroM => write!(f, "Golomb-Rice parameter m is zero"),
+        }
+    }
+}
+
+impl Default for GcsParams {
+    fn default() -> Self {
+        GcsParams::BIP158
+    }
+}
+
+impl Encodable for GcsParams {
+    fn consensus_encode<W: io::Write>(&self, mut writer: W) -> Result<usize, io::Error> {
+        let len = self.p.consensus_encode(&mut writer)?;
+        Ok(len + self.m.consensus_encode(&mut writer)?)
+    }
+}
+
+impl Decodable for GcsParams {
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<Self, encode::Error> {
+        let p = Decodable::consensus_decode(&mut d)?;
+        let m = Decodable::consensus_decode(&mut d)?;
+        GcsParams::new(p, m)
+            .map_err(|_| encode::Error::ParseFailed("invalid Golomb-Rice parameters"))
+    }
+}
 
 // The trait is required to implement common functions
 pub trait FilterWriter {
     fn add_filter_element(&mut self, data: &[u8]);
-    fn is_block_filter(&mut self) -> bool;
-}
-
-impl<'a> FilterWriter for BlockFilterWriter<'a> {
-    fn add_filter_element(&mut self, data: &[u8]) {
-        self.add_element(data);
+    /// Check whether the outpoint is created by a transaction already added to the writer.
+    /// Such outpoints are not resolved, their scripts are already added as outputs.
+    fn is_known_output(&self, _outpoint: &OutPoint) -> bool {
+        false
     }
-    fn is_block_filter(&mut self) -> bool {
-        true
+    /// Handle an input whose spent script can't be resolved, by default the error is returned
+    fn on_missing_utxo(&mut self, _outpoint: &OutPoint, error: Error) -> Result<(), Error> {
+        Err(error)
     }
 }
 
+/// Check whether the script is added to a `FilterVersion::V0` filter
 pub fn is_script_indexable(script: &Script) -> bool {
-    !script.is_empty() && (script.is_v0_p2wsh() || script.is_v0_p2wpkh() || script.is_op_return())
+    FilterVersion::V0.is_script_indexable(script)
 }
 
-pub fn add_output_scripts(writer: &mut dyn FilterWriter, txs: &[Transaction]) {
-    for transaction in txs {
-        for output in &transaction.output {
-            if is_script_indexable(&output.script_pubkey) {
-                writer.add_filter_element(output.script_pubkey.as_bytes());
-            }
+/// SipHash keys of a block filter, taken from the first 16 bytes of the block hash
+pub fn block_filter_keys(block_hash: &BlockHash) -> (u64, u64) {
+    let mut k0 = [0u8; 8];
+    let mut k1 = [0u8; 8];
+    k0.copy_from_slice(&block_hash[0..8]);
+    k1.copy_from_slice(&block_hash[8..16]);
+    (u64::from_le_bytes(k0), u64::from_le_bytes(k1))
+}
+
+pub fn add_tx_output_scripts(
+    writer: &mut dyn FilterWriter,
+    policy: &dyn ScriptPolicy,
+    tx: &Transaction,
+) {
+    for output in &tx.output {
+        if policy.is_script_indexable(&output.script_pubkey) {
+            writer.add_filter_element(output.script_pubkey.as_bytes());
+        }
+    }
+}
+
+/// Serialized outpoint as it is added to outpoint filters
+pub fn outpoint_element(outpoint: &OutPoint) -> Vec<u8> {
+    encode::serialize(outpoint)
+}
+
+/// Add the txid and outpoints spent by the transaction to an outpoint filter.
+///
+/// Txids are added as 32 raw bytes, outpoints as 36 bytes of `outpoint_element`, so they never
+/// collide. Inputs of a coinbase are skipped.
+pub fn add_tx_outpoints(writer: &mut dyn FilterWriter, tx: &Transaction) {
+    writer.add_filter_element(&tx.txid()[..]);
+    if !tx.is_coin_base() {
+        for input in &tx.input {
+            writer.add_filter_element(&outpoint_element(&input.previous_output));
         }
     }
 }
 
-pub fn add_input_scripts<F>(
+pub fn add_tx_input_scripts<F>(
     writer: &mut dyn FilterWriter,
-    txs: &[Transaction],
+    policy: &dyn ScriptPolicy,
+    tx: &Transaction,
     script_for_coin: F,
 ) -> Result<(), Error>
 where
     F: Fn(&OutPoint) -> Result<Script, Error>,
 {
-    // If this is the block filter, skip the coinbase
-    let n = if writer.is_block_filter() { 1 } else { 0 };
-    for script in txs
-        .iter()
-        .skip(n)
-        .flat_map(|t| {
-            t.input.iter().filter_map(|i| {
-                if i.script_sig.is_empty() {
-                    Some(&i.previous_output)
-                } else {
-                    None
-                }
-            })
-        })
-        .map(script_for_coin)
-    {
-        match script {
+    for input in &tx.input {
+        if !policy.is_input_indexable(input) || writer.is_known_output(&input.previous_output) {
+            continue;
+        }
+        match script_for_coin(&input.previous_output) {
             Ok(script) => {
-                if is_script_indexable(&script) {
+                if policy.is_script_indexable(&script) {
                     writer.add_filter_element(script.as_bytes())
                 }
             }
-            Err(e) => {
-                if !writer.is_block_filter() {
-                    // If it's a mempool filter, just skip invalid inputs
-                    return Err(e);
-                }
-            }
+            Err(e) => writer.on_missing_utxo(&input.previous_output, e)?,
         }
     }
     Ok(())
 }
+
+/// Serde helpers for filter contents: hex strings in human readable formats, raw bytes otherwise
+#[cfg(feature = "serde")]
+pub(crate) mod serde_content {
+    use bitcoin::hashes::hex::{FromHex, ToHex};
+    use serde::de::{Deserializer, Error, SeqAccess, Visitor};
+    use serde::Serializer;
+    use std::fmt;
+
+    pub fn serialize<S: Serializer>(content: &[u8], s: S) -> Result<S::Ok, S::Error> {
+        if s.is_human_readable() {
+            s.serialize_str(&content.to_hex())
+        } else {
+            s.serialize_bytes(content)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<u8>, D::Error> {
+        if d.is_human_readable() {
+            d.deserialize_str(ContentVisitor)
+        } else {
+            d.deserialize_byte_buf(ContentVisitor)
+        }
+    }
+
+    struct ContentVisitor;
+
+    impl<'de> Visitor<'de> for ContentVisitor {
+        type Value = Vec<u8>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("hex string or bytes of a filter")
+        }
+
+        fn visit_str<E: Error>(self, v: &str) -> Result<Vec<u8>, E> {
+            Vec::from_hex(v).map_err(E::custom)
+        }
+
+        fn visit_bytes<E: Error>(self, v: &[u8]) -> Result<Vec<u8>, E> {
+            Ok(v.to_vec())
+        }
+
+        fn visit_byte_buf<E: Error>(self, v: Vec<u8>) -> Result<Vec<u8>, E> {
+            Ok(v)
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<u8>, A::Error> {
+            let mut content = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+            while let Some(byte) = seq.next_element()? {
+                content.push(byte);
+            }
+            Ok(content)
+        }
+    }
+}